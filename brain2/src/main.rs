@@ -14,7 +14,7 @@
 #![no_std]
 #![no_main]
 
-pub mod proto;
+use sparklemotion_proto as proto;
 
 use core::net::Ipv4Addr;
 
@@ -27,7 +27,6 @@ use embassy_net::{Runner, Stack, StackResources, tcp::TcpSocket, udp::PacketMeta
 use embassy_sync::blocking_mutex::raw::NoopRawMutex;
 use embassy_time::{Duration, Timer};
 use embedded_hal_async::delay::{self, DelayNs};
-use embedded_io::Write as _;
 use esp_alloc as _;
 use esp_backtrace as _;
 use esp_hal::{clock::CpuClock, rng::Rng, timer::timg::TimerGroup};
@@ -41,9 +40,7 @@ use esp_wifi::{
 };
 use log::info;
 
-use heapless::Vec;
-
-use crate::proto::{Header, VecWriter};
+use crate::proto::{VecWriter, sequence::IdCounter};
 
 esp_bootloader_esp_idf::esp_app_desc!();
 
@@ -203,26 +200,15 @@ async fn udp_task(
     );
     socket.bind(8003).unwrap();
 
-    let mut w = VecWriter { buffer: Vec::new() };
-
-    proto::write_hello_msg(&mut w, &brain_id);
-
-    let mut msg_id = 0i16;
-    let header = Header::from_payload(msg_id, w.buffer.as_slice());
-    msg_id += 1;
-    let mut msg_with_header = VecWriter {
-        buffer: Vec::<u8, 128>::new(),
-    };
-    msg_with_header
-        .write_all(header.to_bytes().as_slice())
-        .unwrap();
-    msg_with_header.write_all(w.buffer.as_slice()).unwrap();
-    info!("hello_msg {:x?}", &msg_with_header.buffer);
-
-    socket
-        .send_to(&msg_with_header.buffer, broadcast)
-        .await
-        .unwrap();
+    let mut w = VecWriter::<128>::new();
+
+    proto::write_hello_msg(&mut w, &brain_id, None, None);
+
+    let mut msg_ids = IdCounter::default();
+    for frame in proto::prepend_header_heapless(msg_ids.next_id(), &w.buffer) {
+        info!("hello_msg {:x?}", &frame);
+        socket.send_to(&frame, broadcast).await.unwrap();
+    }
 
     let mut rx_buf = [0; 4096];
 
@@ -0,0 +1,158 @@
+//! Settings pinky can change at runtime with a Configure message. They are
+//! checked here, applied by main_task and the led_write_tasks, and stored in
+//! NVS by [`crate::settings::Settings`].
+
+use std::{net::Ipv4Addr, ops::RangeInclusive};
+
+use embassy_time::Duration;
+use smart_leds::RGB8;
+
+use crate::{
+    dithering,
+    led_state::ChannelLayout,
+    proto::{
+        ColorOrder, Configure, DmxUniverses,
+        e131::{MAX_UNIVERSE, PIXELS_PER_UNIVERSE},
+    },
+};
+
+/// Refresh rates the led_write_tasks can keep up with. Dithering needs a
+/// high rate to not flicker, so low ones are for debugging.
+pub const MAX_FPS: RangeInclusive<u8> = 1..=120;
+/// Liveness timeouts long enough to not flood pinky with hellos, and short
+/// enough to find it again quickly.
+pub const LIVENESS_TIMEOUT_MS: RangeInclusive<u32> = 1_000..=60_000;
+pub const DEFAULT_LIVENESS_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug)]
+pub enum ConfigError {
+    TooManyLeds {
+        requested: usize,
+        max: usize,
+    },
+    MaxFps(u8),
+    LivenessTimeout(u32),
+    /// Not a multicast address, nor unspecified to leave the group.
+    MulticastGroup(Ipv4Addr),
+    /// Starts at universe 0, runs past the last universe, or has more
+    /// universes than the configured LEDs.
+    DmxUniverses(DmxUniverses),
+}
+
+/// Checks the whole configuration, as [`merge`] gives it, against the
+/// brain's limits, as some depend on more than one field. A message leading
+/// to any bad field is rejected as a whole, so it changes nothing.
+pub fn check(configure: &Configure, max_leds: usize) -> Result<(), ConfigError> {
+    // Without counts every LED is on CH1.
+    let leds = match configure.led_counts {
+        Some(led_counts) => {
+            let requested = led_counts.iter().map(|&count| count as usize).sum();
+            if requested > max_leds {
+                return Err(ConfigError::TooManyLeds {
+                    requested,
+                    max: max_leds,
+                });
+            }
+            requested
+        }
+        None => max_leds,
+    };
+    if let Some(max_fps) = configure.max_fps
+        && !MAX_FPS.contains(&max_fps)
+    {
+        return Err(ConfigError::MaxFps(max_fps));
+    }
+    if let Some(timeout) = configure.liveness_timeout_ms
+        && !LIVENESS_TIMEOUT_MS.contains(&timeout)
+    {
+        return Err(ConfigError::LivenessTimeout(timeout));
+    }
+    if let Some(group) = configure.multicast_group.map(Ipv4Addr::from)
+        && !group.is_multicast()
+        && !group.is_unspecified()
+    {
+        return Err(ConfigError::MulticastGroup(group));
+    }
+    if let Some(universes) = configure.dmx_universes
+        && universes.count > 0
+        && (universes.first == 0
+            || universes.first as u32 + universes.count as u32 - 1 > MAX_UNIVERSE as u32
+            || universes.count as usize > leds.div_ceil(PIXELS_PER_UNIVERSE))
+    {
+        return Err(ConfigError::DmxUniverses(universes));
+    }
+    Ok(())
+}
+
+/// `configure` applied over the configuration `current`.
+pub fn merge(current: &Configure, configure: &Configure) -> Configure {
+    Configure {
+        led_counts: configure.led_counts.or(current.led_counts),
+        max_fps: configure.max_fps.or(current.max_fps),
+        color_order: configure.color_order.or(current.color_order),
+        gamma: configure.gamma.or(current.gamma),
+        brightness: configure.brightness.or(current.brightness),
+        liveness_timeout_ms: configure
+            .liveness_timeout_ms
+            .or(current.liveness_timeout_ms),
+        multicast_group: configure.multicast_group.or(current.multicast_group),
+        dmx_universes: configure.dmx_universes.or(current.dmx_universes),
+    }
+}
+
+/// Universes for E1.31 and Art-Net input until they are configured: from
+/// universe 1, enough for every LED.
+pub fn default_dmx_universes(layout: &ChannelLayout) -> DmxUniverses {
+    let leds: usize = layout.led_counts.iter().sum();
+    DmxUniverses {
+        first: 1,
+        count: leds.div_ceil(PIXELS_PER_UNIVERSE) as u16,
+    }
+}
+
+/// How the led_write_tasks turn a frame into LED data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OutputConfig {
+    pub max_fps: u8,
+    /// Order the colors are sent in.
+    pub color_order: ColorOrder,
+    /// Gamma correction and dithering.
+    pub gamma: bool,
+    pub brightness: u8,
+}
+
+impl OutputConfig {
+    /// What the brain did before it could be configured.
+    pub const DEFAULT: Self = Self {
+        max_fps: 60,
+        color_order: ColorOrder::Rgb,
+        gamma: true,
+        brightness: 255,
+    };
+
+    /// Takes the fields `configure` sets.
+    pub fn update(&mut self, configure: &Configure) {
+        self.max_fps = configure.max_fps.unwrap_or(self.max_fps);
+        self.color_order = configure.color_order.unwrap_or(self.color_order);
+        self.gamma = configure.gamma.unwrap_or(self.gamma);
+        self.brightness = configure.brightness.unwrap_or(self.brightness);
+    }
+
+    pub fn refresh_period(&self) -> Duration {
+        Duration::from_hz(self.max_fps as u64)
+    }
+
+    /// The colors of `rgb` to send, in order, on refresh `frame_number`.
+    pub fn render(&self, rgb: RGB8, frame_number: u32, pixel_index: u32) -> [u8; 3] {
+        let correct = |value: u8| {
+            // +1 so full brightness leaves values as they are.
+            let value = ((value as u16 * (self.brightness as u16 + 1)) >> 8) as u8;
+            if self.gamma {
+                dithering::correct_22(value, frame_number, pixel_index)
+            } else {
+                value
+            }
+        };
+        self.color_order.arrange([rgb.r, rgb.g, rgb.b].map(correct))
+    }
+}
@@ -0,0 +1,99 @@
+//! Frames handed from main_task to one output's led_write_task, either shown
+//! from its next refresh or held until their presentation time.
+
+use std::collections::VecDeque;
+
+use embassy_time::{Duration, Instant};
+use log::debug;
+use smart_leds::RGB8;
+
+/// Most frames waiting for their presentation time on one output. At 60fps
+/// this covers pinky scheduling frames ~60ms ahead.
+const MAX_SCHEDULED: usize = 4;
+
+struct ScheduledFrame {
+    number: u32,
+    present_at: Instant,
+    leds: Vec<RGB8>,
+}
+
+pub struct ChannelFrames {
+    /// The frame being refreshed on the LEDs.
+    leds: Vec<RGB8>,
+    number: u32,
+    /// Frames to swap in at their presentation time, soonest first.
+    scheduled: VecDeque<ScheduledFrame>,
+}
+
+impl ChannelFrames {
+    pub const fn new() -> Self {
+        Self {
+            leds: Vec::new(),
+            number: 0,
+            scheduled: VecDeque::new(),
+        }
+    }
+
+    pub fn leds(&self) -> &[RGB8] {
+        &self.leds
+    }
+
+    /// Frame number of the frame being refreshed.
+    pub fn number(&self) -> u32 {
+        self.number
+    }
+
+    /// Shows `leds` from the next refresh, dropping any scheduled frames.
+    pub fn show(&mut self, number: u32, leds: &[RGB8]) {
+        self.scheduled.clear();
+        self.leds.clear();
+        self.leds.extend_from_slice(leds);
+        self.number = number;
+    }
+
+    /// Holds `leds` until `present_at`.
+    pub fn schedule(&mut self, number: u32, present_at: Instant, leds: &[RGB8]) {
+        // A newer frame due before ones already queued supersedes them.
+        while self
+            .scheduled
+            .back()
+            .is_some_and(|frame| frame.present_at >= present_at)
+        {
+            self.scheduled.pop_back();
+        }
+        if self.scheduled.len() == MAX_SCHEDULED {
+            debug!("too many scheduled frames, dropping the soonest");
+            self.scheduled.pop_front();
+        }
+        self.scheduled.push_back(ScheduledFrame {
+            number,
+            present_at,
+            leds: leds.to_vec(),
+        });
+    }
+
+    /// When the next scheduled frame is due.
+    pub fn next_due(&self) -> Option<Instant> {
+        self.scheduled.front().map(|frame| frame.present_at)
+    }
+
+    /// Swaps in the newest scheduled frame due by `now`, skipping any older
+    /// ones. Returns how long past its presentation time it is.
+    pub fn present_due(&mut self, now: Instant) -> Option<Duration> {
+        let mut late_by = None;
+        while self
+            .scheduled
+            .front()
+            .is_some_and(|frame| frame.present_at <= now)
+        {
+            let frame = self.scheduled.pop_front().unwrap();
+            if late_by.is_some() {
+                debug!("skipping frame {}, a newer one is due", self.number);
+            }
+            late_by = Some(now - frame.present_at);
+            self.leds = frame.leds;
+            self.number = frame.number;
+        }
+        late_by
+    }
+}
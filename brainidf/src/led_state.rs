@@ -0,0 +1,622 @@
+use std::{net::SocketAddr, ops::Range};
+
+use embassy_time::{Duration, Instant};
+use log::{error, info, trace};
+use rgb::AsPixels;
+use smart_leds::RGB8;
+
+use crate::proto::{
+    Channel, Configure, Delta, DeltaOp, Encoding, Header, Message, PANEL_NAME_MAX, PONG_DATA_MAX,
+    Ping, ProtoError, REBOOT_REASON_MAX, Shader,
+    reassembly::{Reassembled, Reassembler},
+    rle_runs,
+    sequence::SeqNum,
+};
+
+pub struct OnMessageResult {
+    pub pong: Option<PongRequest>,
+    pub action: OnMessageAction,
+}
+
+/// Pong data from a shade, to be echoed back once the shade is on the LEDs.
+pub struct PongRequest {
+    pub data: heapless::Vec<u8, PONG_DATA_MAX>,
+    /// When the first frame of the shade arrived.
+    pub received: Instant,
+    /// When the whole shade had arrived.
+    pub reassembled: Instant,
+}
+
+impl OnMessageResult {
+    fn nothing() -> Self {
+        Self {
+            pong: None,
+            action: OnMessageAction::Nothing,
+        }
+    }
+}
+
+pub enum OnMessageAction {
+    Nothing,
+    /// Show the new frame, at `present_at_us` on pinky's clock if set.
+    WriteLeds {
+        present_at_us: Option<u64>,
+    },
+    SendBrainHello,
+    /// Reply to a Ping with this data.
+    SendPong(heapless::Vec<u8, PONG_DATA_MAX>),
+    /// A pong arrived, e.g. to a clock ping.
+    PongReceived {
+        ping: Ping,
+        received: Instant,
+    },
+    /// A delta shade arrived for a frame we don't have, ask its sender for a
+    /// whole one.
+    RequestKeyframe,
+    DownloadFirmware(heapless::String<512>),
+    StoreMapping(Mapping),
+    /// Check and apply settings from pinky.
+    Configure(Configure),
+    /// Flash the identify pattern for this long, or stop if zero.
+    Identify(Duration),
+    /// Restart the brain after `delay`.
+    Reboot {
+        delay: Duration,
+        reason: heapless::String<REBOOT_REASON_MAX>,
+    },
+}
+
+/// Panel identity assigned by the mapper.
+pub struct Mapping {
+    pub brain_id: heapless::String<32>,
+    pub panel_name: Option<heapless::String<PANEL_NAME_MAX>>,
+    pub pixel_count: u32,
+    /// Each pixel's position in the model.
+    pub coordinates: Vec<[f32; 3]>,
+}
+
+/// How long after the mapper's last message the brain goes back to following
+/// pinky.
+const MAPPER_SESSION_TTL: Duration = Duration::from_secs(10);
+
+/// While the mapper is running, only its shades are rendered so pinky can't
+/// overwrite the solid and single-pixel patterns it is photographing.
+struct MapperSession {
+    addr: SocketAddr,
+    last_seen: Instant,
+}
+
+/// After this long without a complete message from a source, its next id is
+/// accepted whatever it is, e.g. after pinky restarts and its ids start over.
+const SEQUENCE_RESET_AFTER: Duration = Duration::from_secs(1);
+/// Most sources whose latest message id is remembered.
+const MAX_SOURCES: usize = 4;
+
+/// Least time between keyframe requests, so a run of deltas on a lossy network
+/// doesn't turn into a request for each.
+const KEYFRAME_REQUEST_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Id of the newest complete message from one sender.
+struct LatestMessage {
+    from: SocketAddr,
+    id: SeqNum,
+    at: Instant,
+}
+
+/// Number of LED data outputs on the brain.
+pub const CHANNELS: usize = 2;
+
+/// How the panel's pixels are split across the LED data outputs: CH1 drives
+/// the first `led_counts[0]`, CH2 the `led_counts[1]` after those.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChannelLayout {
+    pub led_counts: [usize; CHANNELS],
+}
+
+impl ChannelLayout {
+    /// Every LED on CH1, as on single-output brains.
+    pub fn single(n_leds: usize) -> Self {
+        let mut led_counts = [0; CHANNELS];
+        led_counts[0] = n_leds;
+        Self { led_counts }
+    }
+
+    /// Panel pixels driven by output `index` (0 for CH1).
+    pub fn range(&self, index: usize) -> Range<usize> {
+        let start = self.led_counts[..index].iter().sum();
+        start..start + self.led_counts[index]
+    }
+}
+
+/// State machine to handle message unframing. Maintains the current state of
+/// all LEDs.
+pub struct LedState {
+    reassembler: Reassembler,
+    mapper: Option<MapperSession>,
+    latest: heapless::Vec<LatestMessage, MAX_SOURCES>,
+    /// Frames dropped because they belong to a message older than, or the
+    /// same as, one already handled.
+    stale_frames: u32,
+    /// Sender and message id of the shade last drawn into each output's
+    /// LEDs, the base a delta shade for the output must name.
+    last_frame: [Option<(SocketAddr, i16)>; CHANNELS],
+    keyframe_requested_at: Option<Instant>,
+    /// Delta shades dropped because their base frame wasn't the last drawn.
+    missed_deltas: u32,
+    pixel_count: Option<usize>,
+    layout: ChannelLayout,
+    leds: Vec<u8>,
+}
+
+impl LedState {
+    pub fn new(n_leds: usize) -> Self {
+        Self {
+            reassembler: Reassembler::default(),
+            mapper: None,
+            latest: heapless::Vec::new(),
+            stale_frames: 0,
+            last_frame: [None; CHANNELS],
+            keyframe_requested_at: None,
+            missed_deltas: 0,
+            leds: vec![0u8; n_leds * 3],
+            pixel_count: None,
+            layout: ChannelLayout::single(n_leds),
+        }
+    }
+    /// Pixels for output `index` (0 for CH1), up to the panel's pixel count.
+    pub fn get_channel_leds(&self, index: usize) -> &[RGB8] {
+        let range = self.layout.range(index);
+        let end = self
+            .pixel_count
+            .map_or(range.end, |count| count.clamp(range.start, range.end));
+        &self.leds.as_pixels()[range.start..end]
+    }
+    pub fn get_leds(&self) -> &[RGB8] {
+        let pixels = self.leds.as_pixels();
+        if let Some(pixel_count) = self.pixel_count {
+            &pixels[..pixel_count.min(pixels.len())]
+        } else {
+            pixels
+        }
+    }
+    pub fn set_pixel_count(&mut self, pixel_count: Option<usize>) {
+        self.pixel_count = pixel_count;
+    }
+    /// Splits the LEDs across the outputs, shrinking the counts to fit in the
+    /// LED buffer.
+    pub fn set_channel_layout(&mut self, layout: ChannelLayout) {
+        let mut available = self.leds.len() / 3;
+        self.layout = layout;
+        // The outputs' LEDs moved, so deltas can't build on what they showed.
+        self.last_frame = [None; CHANNELS];
+        for count in &mut self.layout.led_counts {
+            *count = (*count).min(available);
+            available -= *count;
+        }
+    }
+    pub fn channel_layout(&self) -> ChannelLayout {
+        self.layout
+    }
+    /// Copies RGB pixels received over a lighting protocol such as E1.31
+    /// into the panel from pixel `start`, as far as the LED buffer goes.
+    pub fn set_pixels(&mut self, start: usize, rgb: &[u8]) {
+        let start = (start * 3).min(self.leds.len());
+        let len = (rgb.len() / 3 * 3).min(self.leds.len() - start);
+        self.leds[start..start + len].copy_from_slice(&rgb[..len]);
+        // Not a frame pinky knows, so its deltas can't build on it.
+        self.last_frame = [None; CHANNELS];
+    }
+    /// Number of messages dropped because a fragment never arrived.
+    pub fn incomplete_messages(&self) -> u32 {
+        self.reassembler.incomplete
+    }
+    /// Number of late or duplicate frames dropped.
+    pub fn stale_frames(&self) -> u32 {
+        self.stale_frames
+    }
+    /// Number of delta shades dropped for want of their base frame.
+    pub fn missed_deltas(&self) -> u32 {
+        self.missed_deltas
+    }
+    /// Whether `from` is the mapper, while a mapping session is running.
+    pub fn is_mapper(&self, from: SocketAddr) -> bool {
+        self.mapper
+            .as_ref()
+            .is_some_and(|mapper| mapper.addr == from)
+    }
+    // Returns: whether caller should write LED data out to RMT
+    pub fn on_message(
+        &mut self,
+        from: SocketAddr,
+        header: Header,
+        rx_packet: &[u8],
+    ) -> OnMessageResult {
+        let now = Instant::now();
+        if let Some(mapper) = &mut self.mapper {
+            if mapper.addr == from {
+                mapper.last_seen = now;
+            } else if now.saturating_duration_since(mapper.last_seen) > MAPPER_SESSION_TTL {
+                info!("Mapper session ended");
+                self.mapper = None;
+            }
+        }
+
+        let id = SeqNum(header.id);
+        if self.is_stale(from, id, now) {
+            trace!("dropping stale frame of message {} from {from}", header.id);
+            self.stale_frames += 1;
+            return OnMessageResult::nothing();
+        }
+
+        let Some((msg, first_seen_us)) =
+            self.reassembler
+                .push(from, &header, rx_packet, now.as_micros())
+        else {
+            return OnMessageResult::nothing();
+        };
+        let first_seen = Instant::from_micros(first_seen_us);
+        self.set_latest(from, id, now);
+        let res = self.on_complete_message(from, header.id, first_seen, now, &msg);
+        if let Reassembled::Buffered(buf) = msg {
+            self.reassembler.recycle(buf);
+        }
+        res
+    }
+
+    /// Whether a frame with `id` belongs to a message no newer than the last
+    /// one completed from `from`.
+    fn is_stale(&self, from: SocketAddr, id: SeqNum, now: Instant) -> bool {
+        self.latest.iter().any(|latest| {
+            latest.from == from
+                && now.saturating_duration_since(latest.at) < SEQUENCE_RESET_AFTER
+                && !id.is_newer_than(latest.id)
+        })
+    }
+
+    fn set_latest(&mut self, from: SocketAddr, id: SeqNum, at: Instant) {
+        let latest = LatestMessage { from, id, at };
+        if let Some(slot) = self.latest.iter_mut().find(|l| l.from == from) {
+            *slot = latest;
+        } else if let Err(latest) = self.latest.push(latest) {
+            let oldest = self.latest.iter_mut().min_by_key(|l| l.at).unwrap();
+            *oldest = latest;
+        }
+    }
+
+    fn on_complete_message(
+        &mut self,
+        from: SocketAddr,
+        id: i16,
+        first_seen: Instant,
+        now: Instant,
+        msg: &[u8],
+    ) -> OnMessageResult {
+        let mut pong = None;
+
+        let shade = match Message::decode(msg) {
+            Ok(Message::BrainPanelShade(shade)) => {
+                if let Some(mapper) = &self.mapper
+                    && mapper.addr != from
+                {
+                    trace!("mapping in progress, ignoring shade from {from}");
+                    return OnMessageResult::nothing();
+                }
+                shade
+            }
+            Ok(Message::MapperHello) => {
+                if self.mapper.is_none() {
+                    info!("Mapper session started by {from}");
+                }
+                self.mapper = Some(MapperSession {
+                    addr: from,
+                    last_seen: now,
+                });
+                return OnMessageResult {
+                    pong: None,
+                    action: OnMessageAction::SendBrainHello,
+                };
+            }
+            Ok(Message::BrainIdRequest) => {
+                return OnMessageResult {
+                    pong: None,
+                    action: OnMessageAction::SendBrainHello,
+                };
+            }
+            Ok(Message::Ping(ping)) if !ping.is_pong => {
+                return OnMessageResult {
+                    pong: None,
+                    action: OnMessageAction::SendPong(ping.data),
+                };
+            }
+            Ok(Message::Ping(ping)) => {
+                return OnMessageResult {
+                    pong: None,
+                    action: OnMessageAction::PongReceived {
+                        ping,
+                        received: first_seen,
+                    },
+                };
+            }
+            Ok(Message::UseFirmware(use_firmware)) => {
+                let Ok(url) = use_firmware.url.try_into() else {
+                    error!("Firmware url too long: {}", use_firmware.url);
+                    return OnMessageResult::nothing();
+                };
+                return OnMessageResult {
+                    action: OnMessageAction::DownloadFirmware(url),
+                    pong: None,
+                };
+            }
+            Ok(Message::BrainMapping(mapping)) => {
+                info!(
+                    "got mapping with {} coordinates",
+                    mapping.coordinates().count()
+                );
+                let (Ok(brain_id), Ok(panel_name)) = (
+                    mapping.brain_id.try_into(),
+                    mapping.panel_name.map(TryInto::try_into).transpose(),
+                ) else {
+                    error!("Mapping brain id or panel name too long");
+                    return OnMessageResult::nothing();
+                };
+                return OnMessageResult {
+                    pong: None,
+                    action: OnMessageAction::StoreMapping(Mapping {
+                        brain_id,
+                        panel_name,
+                        pixel_count: mapping.pixel_count,
+                        coordinates: mapping.coordinates().collect(),
+                    }),
+                };
+            }
+            Ok(Message::Configure(configure)) => {
+                return OnMessageResult {
+                    pong: None,
+                    action: OnMessageAction::Configure(configure),
+                };
+            }
+            Ok(Message::Identify(identify)) => {
+                return OnMessageResult {
+                    pong: None,
+                    action: OnMessageAction::Identify(Duration::from_millis(
+                        identify.duration_ms.into(),
+                    )),
+                };
+            }
+            Ok(Message::Reboot(reboot)) => {
+                let Ok(reason) = reboot.reason.try_into() else {
+                    error!("Reboot reason too long: {}", reboot.reason);
+                    return OnMessageResult::nothing();
+                };
+                return OnMessageResult {
+                    pong: None,
+                    action: OnMessageAction::Reboot {
+                        delay: Duration::from_millis(reboot.delay_ms.into()),
+                        reason,
+                    },
+                };
+            }
+            Ok(msg) => {
+                info!("got unsupported message type {:?}", msg.message_type());
+                return OnMessageResult::nothing();
+            }
+            Err(e) => {
+                error!("Failed to decode message {e:?}");
+                return OnMessageResult::nothing();
+            }
+        };
+
+        if let Some(pong_data) = shade.pong_data {
+            info!("got pong data");
+            if let Ok(data) = heapless::Vec::from_slice(pong_data) {
+                pong = Some(PongRequest {
+                    data,
+                    received: first_seen,
+                    reassembled: now,
+                });
+            } else {
+                error!(
+                    "Pong data of length {} too long, max is {PONG_DATA_MAX}",
+                    pong_data.len()
+                );
+            }
+        }
+
+        let (shader, channel) = match shade
+            .shader()
+            .and_then(|shader| Ok((shader, shade.channel()?)))
+        {
+            Ok(decoded) => decoded,
+            Err(e) => {
+                error!("Failed to decode shader {:x?}: {e:?}", shade.shader_desc);
+                return OnMessageResult {
+                    pong,
+                    action: OnMessageAction::Nothing,
+                };
+            }
+        };
+
+        let is_delta = matches!(
+            shader,
+            Shader::Pixel {
+                encoding: Encoding::Delta,
+                ..
+            }
+        );
+        if let Shader::Pixel {
+            encoding: Encoding::Delta,
+            data,
+            ..
+        } = shader
+            && let Ok(delta) = Delta::decode(data)
+            && self.last_frame[outputs(channel)]
+                .iter()
+                .any(|&last| last != Some((from, delta.base_id)))
+        {
+            trace!(
+                "dropping delta on missing frame {} from {from}",
+                delta.base_id
+            );
+            self.missed_deltas += 1;
+            let action = if self
+                .keyframe_requested_at
+                .is_some_and(|at| now.saturating_duration_since(at) < KEYFRAME_REQUEST_INTERVAL)
+            {
+                OnMessageAction::Nothing
+            } else {
+                self.keyframe_requested_at = Some(now);
+                OnMessageAction::RequestKeyframe
+            };
+            return OnMessageResult { pong, action };
+        }
+
+        if let Err(e) = self.apply_shader(shader, channel) {
+            error!("Failed to apply shader {:x?}: {e:?}", shade.shader_desc);
+            // It may be partly drawn, so deltas can't build on it.
+            self.last_frame[outputs(channel)].fill(None);
+            return OnMessageResult {
+                pong,
+                action: OnMessageAction::Nothing,
+            };
+        }
+        self.last_frame[outputs(channel)].fill(Some((from, id)));
+        if !is_delta {
+            self.keyframe_requested_at = None;
+        }
+
+        OnMessageResult {
+            pong,
+            action: OnMessageAction::WriteLeds {
+                present_at_us: shade.present_at_us,
+            },
+        }
+    }
+
+    fn apply_shader(&mut self, shader: Shader, channel: Channel) -> Result<(), ProtoError> {
+        let range = match channel {
+            Channel::All => 0..self.leds.len() / 3,
+            Channel::Ch1 => self.layout.range(0),
+            Channel::Ch2 => self.layout.range(1),
+        };
+        let leds = &mut self.leds[range.start * 3..range.end * 3];
+        match shader {
+            Shader::Solid { argb } => {
+                // A whole-panel fill stops at the panel's last pixel, a
+                // channel fill covers the channel.
+                let len = match (channel, self.pixel_count) {
+                    (Channel::All, Some(count)) => (count * 3).min(leds.len()),
+                    _ => leds.len(),
+                };
+                fill(&mut leds[..len], &argb[1..4]);
+            }
+            Shader::Pixel {
+                encoding,
+                pixel_count,
+                palette,
+                data,
+            } => {
+                // Pixel counts of channel shades only cover that channel.
+                if channel == Channel::All {
+                    self.pixel_count = Some(pixel_count as usize);
+                }
+                match encoding {
+                    Encoding::DirectArgb => blend_argb(leds, data),
+                    Encoding::DirectRgb => {
+                        let leds_to_copy = data.len().min(leds.len());
+                        leds[..leds_to_copy].copy_from_slice(&data[..leds_to_copy]);
+                    }
+                    // Palette indices packed MSB first. A is ignored.
+                    Encoding::Indexed2 | Encoding::Indexed4 | Encoding::Indexed16 => {
+                        unpack_indexed(
+                            leds,
+                            encoding.bits_per_pixel(),
+                            palette,
+                            data,
+                            pixel_count as usize,
+                        )
+                    }
+                    Encoding::RleRgb => {
+                        let mut pixels = leds.chunks_exact_mut(3);
+                        for (count, color) in rle_runs(data) {
+                            for pixel in pixels.by_ref().take(count) {
+                                pixel.copy_from_slice(&color);
+                            }
+                        }
+                    }
+                    Encoding::Delta => apply_delta(leds, &Delta::decode(data)?)?,
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Indices of the outputs a shade for `channel` draws on.
+fn outputs(channel: Channel) -> Range<usize> {
+    match channel {
+        Channel::All => 0..CHANNELS,
+        Channel::Ch1 => 0..1,
+        Channel::Ch2 => 1..2,
+    }
+}
+
+/// Changes the frame in `leds` as a delta shade describes.
+fn apply_delta(leds: &mut [u8], delta: &Delta) -> Result<(), ProtoError> {
+    match delta.op {
+        DeltaOp::Xor => {
+            let mut pixels = leds.chunks_exact_mut(3);
+            for (count, xor) in rle_runs(delta.data) {
+                for pixel in pixels.by_ref().take(count) {
+                    for (channel, x) in pixel.iter_mut().zip(xor) {
+                        *channel ^= x;
+                    }
+                }
+            }
+        }
+        DeltaOp::Patch => {
+            for patch in delta.patches() {
+                let patch = patch?;
+                let start = (patch.start as usize * 3).min(leds.len());
+                let len = patch.rgb.len().min(leds.len() - start);
+                leds[start..start + len].copy_from_slice(&patch.rgb[..len]);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Alpha-composites ARGB pixels over the current frame, so pinky can send an
+/// overlay without re-sending what's underneath.
+fn blend_argb(leds: &mut [u8], data: &[u8]) {
+    for (pixel, argb) in leds.chunks_exact_mut(3).zip(data.chunks_exact(4)) {
+        let alpha = argb[0] as u16;
+        for (dst, &src) in pixel.iter_mut().zip(&argb[1..]) {
+            *dst = ((src as u16 * alpha + *dst as u16 * (255 - alpha) + 127) / 255) as u8;
+        }
+    }
+}
+
+fn unpack_indexed(
+    leds: &mut [u8],
+    bits_per_pixel: usize,
+    palette: &[u8],
+    data: &[u8],
+    pixel_count: usize,
+) {
+    let pixels_per_byte = 8 / bits_per_pixel;
+    let mask = (1u8 << bits_per_pixel) - 1;
+    let count = pixel_count.min(data.len() * pixels_per_byte);
+    for (led_index, pixel) in leds.chunks_exact_mut(3).take(count).enumerate() {
+        let byte = data[led_index / pixels_per_byte];
+        let shift = 8 - bits_per_pixel * (led_index % pixels_per_byte + 1);
+        let color = ((byte >> shift) & mask) as usize;
+        pixel.copy_from_slice(&palette[color * 4 + 1..color * 4 + 4]);
+    }
+}
+
+/// Sets every LED in `leds` to one RGB color.
+fn fill(leds: &mut [u8], rgb: &[u8]) {
+    for pixel in leds.chunks_exact_mut(3) {
+        pixel.copy_from_slice(rgb);
+    }
+}
@@ -1,14 +1,22 @@
 #![allow(unused)]
+pub mod config;
 pub mod dithering;
+pub mod led_frames;
+pub mod led_state;
 pub mod network_interfaces;
 pub mod ota;
-pub mod proto;
+pub mod settings;
+
+pub use sparklemotion_proto as proto;
 
 use std::{
     f64::MAX,
     io::Write,
-    net::{Ipv4Addr, UdpSocket},
-    sync::Mutex,
+    net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket},
+    sync::{
+        Mutex,
+        atomic::{AtomicU32, Ordering},
+    },
     time::Instant,
 };
 
@@ -16,6 +24,7 @@ use smart_leds::SmartLedsWrite;
 
 use async_io::Async;
 use embassy_executor::{Executor, Spawner};
+use embassy_futures::select::{Either, Either3, Either4, select, select_array, select3, select4};
 use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
 use embassy_time::{Delay, Duration, Timer};
 
@@ -35,22 +44,35 @@ use esp_idf_svc::{
         units::Hertz,
     },
     nvs::EspDefaultNvsPartition,
-    sys::{ESP_TASK_PRIO_MAX, esp_mac_type_t_ESP_MAC_WIFI_STA, esp_read_mac},
+    sys::{
+        ESP_TASK_PRIO_MAX, esp_get_free_heap_size, esp_get_minimum_free_heap_size,
+        esp_mac_type_t_ESP_MAC_WIFI_STA, esp_read_mac, esp_reset_reason,
+    },
     timer::EspTaskTimerService,
     wifi::{AsyncWifi, AuthMethod, ClientConfiguration, Configuration, EspWifi},
 };
-use log::{error, info, trace};
+use log::{debug, error, info, trace};
 use rgb::AsPixels;
 use smart_leds::RGB8;
 use static_cell::StaticCell;
 
 use crate::{
+    config::OutputConfig,
+    led_frames::ChannelFrames,
+    led_state::{CHANNELS, ChannelLayout, LedState, OnMessageAction, PongRequest},
     network_interfaces::{NetworkInterface, connect_eth},
     ota::{running_esp_app_version, running_sparklemotion_version},
     proto::{
-        BrainHello, FRAGMENT_MAX, Header, MessageType, PONG_DATA_MAX, Ping, create_hello_msg,
-        prepend_header, prepend_header_heapless,
+        BrainHello, Capabilities, Configure, DmxUniverses, Encoding, FRAGMENT_MAX, Features,
+        Header, LinkType, MessageType, PROTOCOL_VERSION, Ping, PongTimestamps, REBOOT_REASON_MAX,
+        Telemetry,
+        artnet::{ARTNET_PORT, ArtNetAction, ArtNetReceiver, NodeInfo},
+        clock::SyncedClock,
+        e131::{E131_PORT, E131Receiver},
+        prepend_header_heapless,
+        sequence::IdCounter,
     },
+    settings::Settings,
 };
 
 #[cfg(feature = "wifi")]
@@ -60,14 +82,41 @@ const PASSWORD: &str = env!("PASSWORD");
 const BRAIN_PORT: u16 = 8003;
 const PINKY_PORT: u16 = 8002;
 const MAX_LEDS: usize = 2048;
-
-// The current brain firmware only uses data CH1, though it has a CH2 as well.
-const LED_CH1_GPIO: u8 = 32;
-const LED_CH2_GPIO: u8 = 2;
+/// How often the brain pings pinky to keep its clock estimate current.
+const CLOCK_SYNC_INTERVAL: Duration = Duration::from_secs(1);
+const TELEMETRY_INTERVAL: Duration = Duration::from_secs(5);
+/// Longest a Reboot may wait, so a bad delay can't leave one pending for days.
+const MAX_REBOOT_DELAY: Duration = Duration::from_secs(60);
+/// The identify pattern shows white over the frame for this long, then the
+/// frame for this long, and so on.
+const IDENTIFY_BLINK: Duration = Duration::from_millis(250);
+
+/// SPI bytes for a full channel of LEDs, 4 per color bit, plus the reset.
+const LED_ENCODED_LEN: usize = MAX_LEDS * 12 + 64 * 12;
 
 static EXECUTOR: StaticCell<Executor> = StaticCell::new();
 
-static LED_MUTEX: Mutex<Vec<RGB8>> = Mutex::new(Vec::new());
+/// Frames for each output, CH1 first.
+static LED_MUTEX: [Mutex<ChannelFrames>; CHANNELS] =
+    [const { Mutex::new(ChannelFrames::new()) }; CHANNELS];
+/// How the led_write_tasks render frames, as last configured by pinky.
+static OUTPUT_CONFIG: Mutex<OutputConfig> = Mutex::new(OutputConfig::DEFAULT);
+/// Until when the led_write_tasks flash the identify pattern.
+static IDENTIFY_UNTIL: Mutex<Option<embassy_time::Instant>> = Mutex::new(None);
+/// Wakes an output's led_write_task when a frame is scheduled, as it may be
+/// due before the next refresh.
+static FRAME_QUEUED: [Signal<CriticalSectionRawMutex, ()>; CHANNELS] =
+    [const { Signal::new() }; CHANNELS];
+/// Scheduled frames shown more than LATE_FRAME_THRESHOLD after their
+/// presentation time, summed over outputs.
+static LATE_FRAMES: AtomicU32 = AtomicU32::new(0);
+/// New frames handed to the LED drivers, summed over outputs.
+static FRAMES_RENDERED: AtomicU32 = AtomicU32::new(0);
+const LATE_FRAME_THRESHOLD: Duration = Duration::from_millis(2);
+/// Set by the led_write_task of each output when a new frame is handed to its
+/// LED driver: the frame number and when.
+static FRAME_DISPLAYED: [Signal<CriticalSectionRawMutex, (u32, embassy_time::Instant)>; CHANNELS] =
+    [const { Signal::new() }; CHANNELS];
 
 fn main() {
     esp_idf_svc::sys::link_patches();
@@ -88,17 +137,15 @@ fn main() {
 }
 
 // This task is blocking since esp-hal-idf doesn't support non-blocking writes
-// to RMT.
-// TODO: consider pinning this task to Core1
+// to RMT. One runs per output, `channel` being its index in LED_MUTEX.
 fn led_write_task(
+    channel: usize,
     data_gpio: impl Peripheral<P = impl OutputPin>,
-    rmt: impl Peripheral<P = impl RmtChannel>,
     spi: impl Peripheral<P = impl SpiAnyPins>,
-    task_watchdog_timer: impl Peripheral<P = TWDT>,
+    dma: Dma,
 ) {
     // SPI/DMA Config
-    const ENCODED_LEN: usize = MAX_LEDS * 12 + 64 * 12;
-    let config = SpiDriverConfig::new().dma(esp_idf_svc::hal::spi::Dma::Channel1(ENCODED_LEN));
+    let config = SpiDriverConfig::new().dma(dma);
     let spi_driver = SpiDriver::new_without_sclk(
         spi,
         data_gpio,
@@ -108,34 +155,76 @@ fn led_write_task(
     .unwrap();
     let spi_config = SpiConfig::new().write_only(true).baudrate(Hertz(3_000_000));
     let spi_driver = SpiBusDriver::new(spi_driver, &spi_config).unwrap();
-    let max_framerate = 60;
     let mut frame_number = 0u64;
-    let mut dma_buf = vec![0u8; ENCODED_LEN];
+    let mut dma_buf = vec![0u8; LED_ENCODED_LEN];
     let mut ws_driver = ws2812_spi::prerendered::Ws2812::new(spi_driver, &mut dma_buf);
 
-    let mut frame_ticker = embassy_time::Ticker::every(Duration::from_hz(max_framerate));
+    let mut next_refresh = embassy_time::Instant::now();
     let mut leds = vec![];
+    let mut displayed_frame = 0;
+    // LEDs written by the last refresh, to blank those left off when the LED
+    // count is lowered.
+    let mut written = 0;
 
     // Initialize to black
     ws_driver.write(std::iter::repeat_n(RGB8::new(255, 255, 255), MAX_LEDS));
 
     loop {
-        block_on(frame_ticker.next());
-        {
-            let data = LED_MUTEX.lock().unwrap();
-            leds.resize(data.len(), Default::default());
-            leds.copy_from_slice(data.as_slice());
+        let output = *OUTPUT_CONFIG.lock().unwrap();
+        let refresh_period = output.refresh_period();
+        // Refresh at max_fps for dithering, and in between when a
+        // scheduled frame is due.
+        let due = LED_MUTEX[channel].lock().unwrap().next_due();
+        let wake_at = due.map_or(next_refresh, |due| due.min(next_refresh));
+        block_on(select(Timer::at(wake_at), FRAME_QUEUED[channel].wait()));
+        let now = embassy_time::Instant::now();
+        if now < wake_at {
+            // A frame was scheduled, it may be due sooner.
+            continue;
         }
+        if now >= next_refresh {
+            next_refresh += refresh_period;
+            if next_refresh < now {
+                next_refresh = now + refresh_period;
+            }
+        }
+        let frame = {
+            let mut frames = LED_MUTEX[channel].lock().unwrap();
+            if let Some(late_by) = frames.present_due(now)
+                && late_by > LATE_FRAME_THRESHOLD
+            {
+                LATE_FRAMES.fetch_add(1, Ordering::Relaxed);
+                debug!("frame {} late by {late_by}", frames.number());
+            }
+            leds.clear();
+            leds.extend_from_slice(frames.leds());
+            frames.number()
+        };
         trace!("got led frame");
-        let dithered = leds.iter().enumerate().map(|(pixel_idx, rgb)| {
-            // FLIP R and G for the spi driver
-            RGB8::new(
-                dithering::correct_22(rgb.g, frame_number as u32, pixel_idx as u32),
-                dithering::correct_22(rgb.r, frame_number as u32, pixel_idx as u32),
-                dithering::correct_22(rgb.b, frame_number as u32, pixel_idx as u32),
-            )
+        let identify = IDENTIFY_UNTIL
+            .lock()
+            .unwrap()
+            .is_some_and(|until| now < until)
+            && (now.as_millis() / IDENTIFY_BLINK.as_millis()) % 2 == 0;
+        let rendered = leds.iter().enumerate().map(|(pixel_idx, &rgb)| {
+            let rgb = if identify {
+                RGB8::new(255, 255, 255)
+            } else {
+                rgb
+            };
+            let [first, second, third] = output.render(rgb, frame_number as u32, pixel_idx as u32);
+            // The spi driver sends g, r, b.
+            RGB8::new(second, first, third)
         });
-        ws_driver.write(dithered).unwrap();
+        let blanked = std::iter::repeat_n(RGB8::default(), written.saturating_sub(leds.len()));
+        let handed_off_at = embassy_time::Instant::now();
+        ws_driver.write(rendered.chain(blanked)).unwrap();
+        written = leds.len();
+        if frame != displayed_frame {
+            displayed_frame = frame;
+            FRAMES_RENDERED.fetch_add(1, Ordering::Relaxed);
+            FRAME_DISPLAYED[channel].signal((frame, handed_off_at));
+        }
 
         frame_number += 1;
     }
@@ -147,9 +236,13 @@ async fn main_task() {
     let peripherals = Peripherals::take().unwrap();
     let sys_loop = EspSystemEventLoop::take().unwrap();
     let timer_service = EspTaskTimerService::new().unwrap();
+    let nvs = EspDefaultNvsPartition::take().unwrap();
+    let mut settings = Settings::new(nvs.clone()).unwrap();
 
-    let led_pin = peripherals.pins.gpio32;
-    let channel = peripherals.rmt.channel0;
+    // LED data outputs. Each has its own SPI bus, DMA channel and writer
+    // thread.
+    let ch1_pin = peripherals.pins.gpio32;
+    let ch2_pin = peripherals.pins.gpio2;
 
     ThreadSpawnConfiguration {
         pin_to_core: Some(Core::Core1),
@@ -158,13 +251,47 @@ async fn main_task() {
     }
     .set();
     std::thread::spawn(move || {
-        led_write_task(led_pin, channel, peripherals.spi2, peripherals.twdt)
+        led_write_task(0, ch1_pin, peripherals.spi2, Dma::Channel1(LED_ENCODED_LEN))
+    });
+    std::thread::spawn(move || {
+        led_write_task(1, ch2_pin, peripherals.spi3, Dma::Channel2(LED_ENCODED_LEN))
     });
     ThreadSpawnConfiguration::default().set();
     let mut led_state = LedState::new(MAX_LEDS);
+    led_state.set_pixel_count(settings.pixel_count().map(|count| count as usize));
+    let mut pinky_liveness_ttl = config::DEFAULT_LIVENESS_TIMEOUT;
+    // Group to join, as well as listening to broadcast.
+    let mut multicast_group: Option<Ipv4Addr> = None;
+    // E1.31 universes, if not the default.
+    let mut dmx_universes: Option<DmxUniverses> = None;
+    // Everything set by Configure so far, to check the next one against.
+    let mut current_config = Configure::default();
+    let stored_config = settings.config();
+    match config::check(&stored_config, MAX_LEDS) {
+        Ok(()) => {
+            apply_config(
+                &stored_config,
+                &mut led_state,
+                &mut pinky_liveness_ttl,
+                &mut multicast_group,
+                &mut dmx_universes,
+            );
+            current_config = stored_config;
+        }
+        Err(e) => error!("Ignoring stored configuration {stored_config:?}: {e:?}"),
+    }
+    info!("LED channels {:?}", led_state.channel_layout());
+    let mut e131 = E131Receiver::new(
+        dmx_universes.unwrap_or_else(|| config::default_dmx_universes(&led_state.channel_layout())),
+    );
+    info!("E1.31 universes {:?}", e131.span());
+    // Art-Net takes the same universes, numbered from 0.
+    let mut artnet = ArtNetReceiver::new(e131.span());
+    let mut panel_name = settings.panel_name();
+    info!("Panel name {panel_name:?}");
 
     #[cfg(feature = "ethernet")]
-    let network_if = network_interfaces::setup_eth_driver(
+    let mut network_if = network_interfaces::setup_eth_driver(
         peripherals.mac,
         peripherals.pins.gpio25,
         peripherals.pins.gpio26,
@@ -180,15 +307,33 @@ async fn main_task() {
         &timer_service,
     );
     #[cfg(feature = "wifi")]
-    let network_if =
-        network_interfaces::setup_wifi_driver(peripherals.modem, &sys_loop, &timer_service);
-
-    let mut msg_id = 0i16;
+    let mut network_if =
+        network_interfaces::setup_wifi_driver(peripherals.modem, &sys_loop, &timer_service, nvs);
+
+    let mut msg_ids = IdCounter::default();
+    let reset_reason = unsafe { esp_reset_reason() } as u8;
+    let reboot_reason = settings.take_reboot_reason();
+    if let Some(reason) = &reboot_reason {
+        info!("Rebooted by pinky: {reason}");
+    }
+    // Network frames received, and how many of those didn't parse.
+    let mut frames_received = 0u32;
+    let mut malformed_frames = 0u32;
+    // Number of the last frame handed to the led_write_tasks.
+    let mut frame_number = 0u32;
+    // Pinky's clock, to turn a shade's presentation time into ours. Until it
+    // is known, those shades are shown on arrival.
+    let mut pinky_clock = SyncedClock::default();
+    // Where pinky sends from, so where to send clock pings. Known from its
+    // first message, so the clock is synced before a shade is scheduled.
+    let mut pinky_addr: Option<SocketAddr> = None;
+    // When to restart and why, as asked by the last Reboot message.
+    let mut reboot: Option<(embassy_time::Instant, heapless::String<REBOOT_REASON_MAX>)> = None;
     loop {
         // Connect logic takes temporary ownership and passes it back.
         // TODO: make outer_connect name better, runs connection logic, eth/wifi
         // agnostic.
-        let network_if = network_if.outer_connect().await.unwrap();
+        network_if = network_if.outer_connect().await.unwrap();
         let bcast_addr = network_if.get_broadcast();
 
         info!("broadcast addr: {bcast_addr:?}");
@@ -202,73 +347,406 @@ async fn main_task() {
 
         info!("Running version {:?}", running_sparklemotion_version());
         let firmware_version = ota::running_sparklemotion_version();
-        let hello_msg = create_hello_msg(msg_id, &brain_id, firmware_version.as_deref());
-        msg_id = msg_id.wrapping_add_unsigned(1);
+        let hello_msg = brain_hello(
+            &brain_id,
+            panel_name.as_deref(),
+            firmware_version.as_deref(),
+        );
 
         info!("hello_msg {:x?}", &hello_msg);
 
         let udp_sock = Async::<UdpSocket>::bind(([0, 0, 0, 0], BRAIN_PORT)).unwrap();
-
-        udp_sock
-            .send_to(&hello_msg, (bcast_addr, PINKY_PORT))
-            .await
-            .unwrap();
+        // Joined on every connect, as the new socket starts out in no groups.
+        if let Some(group) = multicast_group {
+            join_multicast_group(&udp_sock, group, network_if.get_ip());
+        }
+        let e131_sock = Async::<UdpSocket>::bind(([0, 0, 0, 0], E131_PORT)).unwrap();
+        for group in e131.groups() {
+            join_multicast_group(&e131_sock, group, network_if.get_ip());
+        }
+        let artnet_sock = Async::<UdpSocket>::bind(([0, 0, 0, 0], ARTNET_PORT)).unwrap();
+
+        send_to_pinky(
+            &udp_sock,
+            msg_ids.next_id(),
+            &hello_msg,
+            bcast_addr,
+            multicast_group,
+        )
+        .await
+        .unwrap();
 
         let rx_buf = &mut [0u8; 4096];
-
-        let pinky_liveness_ttl = Duration::from_millis(5_000);
-        let mut next_pong_data = None;
+        let e131_buf = &mut [0u8; 1024];
+        let artnet_buf = &mut [0u8; 1024];
+
+        let mut pinky_deadline = embassy_time::Instant::now() + pinky_liveness_ttl;
+        // Where pinky last sent from, until it goes quiet. Telemetry goes
+        // there rather than to every host on the subnet.
+        let mut pinky_ip: Option<IpAddr> = None;
+        let mut clock_ticker = embassy_time::Ticker::every(CLOCK_SYNC_INTERVAL);
+        let mut telemetry_ticker = embassy_time::Ticker::every(TELEMETRY_INTERVAL);
+        let mut next_pong: Option<PongRequest> = None;
+        // Pong waiting for its frame to reach the LEDs: frame number, where to
+        // send it, and the request.
+        let mut pending_pong: Option<(u32, SocketAddr, PongRequest)> = None;
 
         loop {
             let udp_rx_with_timeout =
-                embassy_time::with_timeout(pinky_liveness_ttl, udp_sock.recv_from(rx_buf));
-            match udp_rx_with_timeout.await {
+                embassy_time::with_deadline(pinky_deadline, udp_sock.recv_from(rx_buf));
+            let rx = match select4(
+                select3(
+                    udp_rx_with_timeout,
+                    e131_sock.recv_from(e131_buf),
+                    artnet_sock.recv_from(artnet_buf),
+                ),
+                select_array(FRAME_DISPLAYED.each_ref().map(Signal::wait)),
+                select(
+                    clock_ticker.next(),
+                    // Never fires while no reboot is scheduled.
+                    Timer::at(
+                        reboot
+                            .as_ref()
+                            .map_or(embassy_time::Instant::MAX, |(at, _)| *at),
+                    ),
+                ),
+                telemetry_ticker.next(),
+            )
+            .await
+            {
+                Either4::First(Either3::First(rx)) => {
+                    pinky_deadline = embassy_time::Instant::now() + pinky_liveness_ttl;
+                    rx
+                }
+                Either4::First(Either3::Second(rx)) => {
+                    match rx {
+                        Ok((count, _)) => {
+                            let changed = e131.on_packet(
+                                &e131_buf[..count],
+                                embassy_time::Instant::now().as_micros(),
+                                |start, rgb| led_state.set_pixels(start, rgb),
+                                |group| {
+                                    join_multicast_group(&e131_sock, group, network_if.get_ip())
+                                },
+                            );
+                            if changed {
+                                frame_number = frame_number.wrapping_add(1);
+                                queue_frame(&led_state, frame_number, None);
+                            }
+                        }
+                        Err(e) => error!("Unhandled E1.31 rx error {e:?}"),
+                    }
+                    continue;
+                }
+                Either4::First(Either3::Third(rx)) => {
+                    let (count, from) = match rx {
+                        Ok(rx) => rx,
+                        Err(e) => {
+                            error!("Unhandled Art-Net rx error {e:?}");
+                            continue;
+                        }
+                    };
+                    match artnet.on_packet(
+                        &artnet_buf[..count],
+                        from.ip(),
+                        embassy_time::Instant::now().as_micros(),
+                        |start, rgb| led_state.set_pixels(start, rgb),
+                    ) {
+                        ArtNetAction::Nothing => {}
+                        ArtNetAction::ReplyToPoll => {
+                            let long_name = match &panel_name {
+                                Some(panel_name) => {
+                                    format!("SparkleMotion brain {brain_id} ({panel_name})")
+                                }
+                                None => format!("SparkleMotion brain {brain_id}"),
+                            };
+                            let node = NodeInfo {
+                                ip: network_if.get_ip().octets(),
+                                mac: *mac,
+                                short_name: &brain_id,
+                                long_name: &long_name,
+                            };
+                            // Art-Net 4 replies go to the subnet broadcast.
+                            for reply in artnet.poll_replies(&node) {
+                                if let Err(e) =
+                                    artnet_sock.send_to(&reply, (bcast_addr, ARTNET_PORT)).await
+                                {
+                                    error!("Failed to send ArtPollReply {e:?}");
+                                }
+                            }
+                        }
+                        ArtNetAction::ShowFrame => {
+                            frame_number = frame_number.wrapping_add(1);
+                            queue_frame(&led_state, frame_number, None);
+                        }
+                    }
+                    continue;
+                }
+                Either4::Fourth(()) => {
+                    // The sockets and their group memberships don't survive
+                    // the link going down, so start over once it's back.
+                    if !network_if.is_up() {
+                        error!("Network link lost, reconnecting");
+                        break;
+                    }
+                    let msg = telemetry(
+                        &led_state,
+                        frames_received,
+                        malformed_frames,
+                        reset_reason,
+                        reboot_reason.clone(),
+                        &network_if,
+                    );
+                    let _ = match pinky_ip {
+                        Some(ip) => {
+                            let to = (ip, PINKY_PORT).into();
+                            send_msg(&udp_sock, msg_ids.next_id(), &msg.to_heapless(), to).await
+                        }
+                        None => {
+                            send_to_pinky(
+                                &udp_sock,
+                                msg_ids.next_id(),
+                                &msg.to_heapless(),
+                                bcast_addr,
+                                multicast_group,
+                            )
+                            .await
+                        }
+                    };
+                    continue;
+                }
+                Either4::Third(Either::Second(())) => {
+                    // Stored only now, so a reset before this reports its own
+                    // reason.
+                    if let Some((_, reason)) = reboot.take()
+                        && let Err(e) = settings.set_reboot_reason(&reason)
+                    {
+                        error!("Failed to store reboot reason {e:?}");
+                    }
+                    info!("Rebooting");
+                    esp_idf_svc::hal::reset::restart();
+                }
+                Either4::Third(Either::First(())) => {
+                    if let Some(to) = pinky_addr {
+                        let msg = Ping {
+                            data: SyncedClock::ping_data(embassy_time::Instant::now().as_micros()),
+                            is_pong: false,
+                            timestamps: None,
+                        };
+                        let _ =
+                            send_msg(&udp_sock, msg_ids.next_id(), &msg.to_heapless(), to).await;
+                    }
+                    continue;
+                }
+                Either4::Second(((frame, displayed_at), _)) => {
+                    // Frame numbers only go up, so a later frame being
+                    // displayed means ours was too, or was replaced by it.
+                    if let Some((_, to, pong)) = pending_pong
+                        .take_if(|(pending, ..)| frame.wrapping_sub(*pending) as i32 >= 0)
+                    {
+                        info!("sending pong");
+                        let msg = Ping {
+                            data: pong.data,
+                            is_pong: true,
+                            timestamps: Some(PongTimestamps {
+                                received_us: pong.received.as_micros(),
+                                reassembled_us: pong.reassembled.as_micros(),
+                                displayed_us: displayed_at.as_micros(),
+                            }),
+                        };
+                        let _ =
+                            send_msg(&udp_sock, msg_ids.next_id(), &msg.to_heapless(), to).await;
+                    }
+                    continue;
+                }
+            };
+            match rx {
                 Ok(Ok((count, from))) => {
-                    let mut rx_packet = &rx_buf[..count];
-                    let header = Header::from_reader(&mut rx_packet);
-                    msg_id = header.id.wrapping_add_unsigned(1);
-                    let res = led_state.on_message(header, rx_packet);
-                    if let Some(pong_data) = res.pong_data {
+                    frames_received = frames_received.wrapping_add(1);
+                    let (header, rx_packet) = match Header::decode(&rx_buf[..count]) {
+                        Ok(frame) => frame,
+                        Err(e) => {
+                            malformed_frames = malformed_frames.wrapping_add(1);
+                            error!("Dropping malformed packet from {from}: {e:?}");
+                            continue;
+                        }
+                    };
+                    let res = led_state.on_message(from, header, rx_packet);
+                    // Only pinky sends these; pings and the like may come from
+                    // any tool on the network.
+                    let from_pinky = matches!(
+                        res.action,
+                        OnMessageAction::WriteLeds { .. }
+                            | OnMessageAction::StoreMapping(_)
+                            | OnMessageAction::Configure(_)
+                            | OnMessageAction::Identify(_)
+                            | OnMessageAction::Reboot { .. }
+                            | OnMessageAction::DownloadFirmware(_)
+                    );
+                    if from_pinky && !led_state.is_mapper(from) {
+                        pinky_ip = Some(from.ip());
+                        pinky_addr = Some(from);
+                    }
+                    if let Some(pong) = res.pong {
                         info!("save pong data");
-                        next_pong_data = Some(pong_data);
+                        next_pong = Some(pong);
                     }
                     match res.action {
                         OnMessageAction::Nothing => {}
-                        OnMessageAction::WriteLeds => {
-                            let leds = led_state.get_leds();
+                        OnMessageAction::WriteLeds { present_at_us } => {
                             trace!("sent led frame");
-                            {
-                                let mut locked_leds = LED_MUTEX.lock().unwrap();
-                                locked_leds.clear();
-                                locked_leds.extend_from_slice(&leds);
+                            frame_number = frame_number.wrapping_add(1);
+                            let present_at = present_at_us
+                                .and_then(|pinky_us| pinky_clock.to_local(pinky_us))
+                                .map(embassy_time::Instant::from_micros);
+                            queue_frame(&led_state, frame_number, present_at);
+
+                            // Sent once led_write_task reports the frame displayed.
+                            if let Some(pong) = next_pong.take() {
+                                if pending_pong.is_some() {
+                                    debug!("dropping pong replaced before it was displayed");
+                                }
+                                pending_pong = Some((frame_number, from, pong));
                             }
-
-                            if let Some(next_pong_data) = next_pong_data.take() {
-                                info!("sending pong");
-                                let msg = Ping {
-                                    data: next_pong_data,
-                                    is_pong: true,
-                                };
-                                let Ok(msg_heapless) =
-                                    heapless::Vec::<u8, FRAGMENT_MAX>::from_slice(
-                                        &msg.to_heapless(),
-                                    )
-                                else {
-                                    error!("message copy failed");
-                                    continue;
-                                };
-                                let msg = prepend_header_heapless(msg_id, msg_heapless);
-                                msg_id = msg_id.wrapping_add_unsigned(1);
-                                udp_sock.send_to(&msg, from).await;
+                        }
+                        OnMessageAction::PongReceived { ping, received } => {
+                            if pinky_clock.on_pong(
+                                &ping.data,
+                                ping.timestamps.as_ref(),
+                                received.as_micros(),
+                            ) {
+                                trace!(
+                                    "pinky clock offset {:?}us, drift {:.1}ppm",
+                                    pinky_clock.offset_us(received.as_micros()),
+                                    pinky_clock.drift() * 1e6
+                                );
                             }
                         }
+                        OnMessageAction::SendPong(data) => {
+                            trace!("answering ping from {from}");
+                            let msg = Ping {
+                                data,
+                                is_pong: true,
+                                timestamps: None,
+                            };
+                            let _ =
+                                send_msg(&udp_sock, msg_ids.next_id(), &msg.to_heapless(), from)
+                                    .await;
+                        }
+                        OnMessageAction::RequestKeyframe => {
+                            debug!("requesting keyframe from {from}");
+                            let _ = send_msg(
+                                &udp_sock,
+                                msg_ids.next_id(),
+                                &[MessageType::KeyframeRequest as u8],
+                                from,
+                            )
+                            .await;
+                        }
                         OnMessageAction::SendBrainHello => {
-                            let msg =
-                                create_hello_msg(msg_id, &brain_id, firmware_version.as_deref());
-                            msg_id = msg_id.wrapping_add_unsigned(1);
+                            let msg = brain_hello(
+                                &brain_id,
+                                panel_name.as_deref(),
+                                firmware_version.as_deref(),
+                            );
                             // TODO: log error
                             // NOTE: broadcast didn't work here
-                            let _ = udp_sock.send_to(&msg, from).await;
+                            let _ = send_msg(&udp_sock, msg_ids.next_id(), &msg, from).await;
+                        }
+                        OnMessageAction::StoreMapping(mapping) => {
+                            if mapping.brain_id != brain_id.as_str() {
+                                info!("Ignoring mapping for brain {}", mapping.brain_id);
+                                continue;
+                            }
+                            info!(
+                                "<- Mapped as {:?} with {} pixels",
+                                mapping.panel_name, mapping.pixel_count
+                            );
+                            if let Err(e) = settings.set_mapping(
+                                mapping.panel_name.as_deref(),
+                                mapping.pixel_count,
+                                &mapping.coordinates,
+                            ) {
+                                error!("Failed to store mapping {e:?}");
+                            }
+                            led_state.set_pixel_count(Some(mapping.pixel_count as usize));
+                            panel_name = mapping.panel_name;
+
+                            // Let pinky know who we are now.
+                            let hello_msg = brain_hello(
+                                &brain_id,
+                                panel_name.as_deref(),
+                                firmware_version.as_deref(),
+                            );
+                            let _ = send_to_pinky(
+                                &udp_sock,
+                                msg_ids.next_id(),
+                                &hello_msg,
+                                bcast_addr,
+                                multicast_group,
+                            )
+                            .await;
+                        }
+                        OnMessageAction::Configure(configure) => {
+                            let merged = config::merge(&current_config, &configure);
+                            if let Err(e) = config::check(&merged, MAX_LEDS) {
+                                error!("Rejecting configuration from {from}: {e:?}");
+                                continue;
+                            }
+                            current_config = merged;
+                            info!("<- Configure {configure:?}");
+                            let old_group = multicast_group;
+                            apply_config(
+                                &configure,
+                                &mut led_state,
+                                &mut pinky_liveness_ttl,
+                                &mut multicast_group,
+                                &mut dmx_universes,
+                            );
+                            let span = dmx_universes.unwrap_or_else(|| {
+                                config::default_dmx_universes(&led_state.channel_layout())
+                            });
+                            if span != e131.span() {
+                                for group in e131.groups() {
+                                    leave_multicast_group(&e131_sock, group, network_if.get_ip());
+                                }
+                                e131 = E131Receiver::new(span);
+                                for group in e131.groups() {
+                                    join_multicast_group(&e131_sock, group, network_if.get_ip());
+                                }
+                                artnet = ArtNetReceiver::new(span);
+                            }
+                            if multicast_group != old_group {
+                                if let Some(group) = old_group {
+                                    leave_multicast_group(&udp_sock, group, network_if.get_ip());
+                                }
+                                if let Some(group) = multicast_group {
+                                    join_multicast_group(&udp_sock, group, network_if.get_ip());
+                                }
+                            }
+                            pinky_deadline = embassy_time::Instant::now() + pinky_liveness_ttl;
+                            if let Err(e) = settings.set_config(&configure) {
+                                error!("Failed to store configuration {e:?}");
+                            }
+                        }
+                        OnMessageAction::Identify(duration) => {
+                            info!("<- Identify for {duration:?}");
+                            *IDENTIFY_UNTIL.lock().unwrap() = (duration.as_ticks() > 0)
+                                .then(|| embassy_time::Instant::now() + duration);
+                            // Outputs that haven't been sent a frame yet
+                            // flash over all of their LEDs.
+                            for (channel, frames) in LED_MUTEX.iter().enumerate() {
+                                let mut frames = frames.lock().unwrap();
+                                if frames.leds().is_empty() && frames.next_due().is_none() {
+                                    frames.show(frame_number, led_state.get_channel_leds(channel));
+                                }
+                            }
+                        }
+                        OnMessageAction::Reboot { delay, reason } => {
+                            let delay = delay.min(MAX_REBOOT_DELAY);
+                            info!("<- Reboot in {delay:?}: {reason}");
+                            // A later Reboot replaces this one.
+                            reboot = Some((embassy_time::Instant::now() + delay, reason));
                         }
                         OnMessageAction::DownloadFirmware(url) => {
                             if option_env!("NO_OTA").is_some() {
@@ -299,227 +777,194 @@ async fn main_task() {
                     error!("Unhandled network rx error {e:?}");
                 }
                 Err(_) => {
+                    pinky_deadline = embassy_time::Instant::now() + pinky_liveness_ttl;
+                    pinky_ip = None;
                     info!("Haven't heard from pinky in {pinky_liveness_ttl:?}, sending hello");
-                    let hello_msg =
-                        create_hello_msg(msg_id, &brain_id, firmware_version.as_deref());
-                    msg_id = msg_id.wrapping_add_unsigned(1);
-                    udp_sock
-                        .send_to(&hello_msg, (bcast_addr, PINKY_PORT))
-                        .await
-                        .unwrap();
+                    let hello_msg = brain_hello(
+                        &brain_id,
+                        panel_name.as_deref(),
+                        firmware_version.as_deref(),
+                    );
+                    // Fails while the link is down, until the telemetry tick
+                    // notices and reconnects.
+                    if let Err(e) = send_to_pinky(
+                        &udp_sock,
+                        msg_ids.next_id(),
+                        &hello_msg,
+                        bcast_addr,
+                        multicast_group,
+                    )
+                    .await
+                    {
+                        error!("Failed to send hello {e:?}");
+                    }
                 }
             }
         }
-
-        while NetworkInterface::is_up(&network_if) {
-            embassy_time::Delay.delay_ms(1000).await;
-        }
     }
 }
 
-struct OnMessageResult {
-    pub pong_data: Option<heapless::Vec<u8, PONG_DATA_MAX>>,
-    pub action: OnMessageAction,
-}
-enum OnMessageAction {
-    Nothing,
-    WriteLeds,
-    SendBrainHello,
-    DownloadFirmware(heapless::String<512>),
-}
-
-/// State machine to handle message unframing. Maintains the current state of
-/// all LEDs with no additional memory overhead.
-struct LedState {
-    last_header: Option<Header>,
-    last_led_byte_idx: Option<usize>,
-    pixel_count: Option<usize>,
-    leds: Vec<u8>,
-    palette: Option<Vec<u8>>,
+/// Applies a checked Configure to main_task's state and the
+/// led_write_tasks, which pick it up on their next refresh.
+fn apply_config(
+    configure: &Configure,
+    led_state: &mut LedState,
+    liveness_ttl: &mut Duration,
+    multicast_group: &mut Option<Ipv4Addr>,
+    dmx_universes: &mut Option<DmxUniverses>,
+) {
+    if let Some(led_counts) = configure.led_counts {
+        led_state.set_channel_layout(ChannelLayout {
+            led_counts: led_counts.map(usize::from),
+        });
+    }
+    if let Some(timeout) = configure.liveness_timeout_ms {
+        *liveness_ttl = Duration::from_millis(timeout.into());
+    }
+    if let Some(group) = configure.multicast_group.map(Ipv4Addr::from) {
+        *multicast_group = (!group.is_unspecified()).then_some(group);
+    }
+    if let Some(universes) = configure.dmx_universes {
+        *dmx_universes = Some(universes);
+    }
+    OUTPUT_CONFIG.lock().unwrap().update(configure);
 }
 
-impl LedState {
-    pub fn new(n_leds: usize) -> Self {
-        Self {
-            last_header: None,
-            last_led_byte_idx: None,
-            leds: vec![0u8; n_leds * 3],
-            pixel_count: None,
-            palette: None,
+/// Hands the frame in `led_state` to the led_write_tasks, to show from
+/// their next refresh or at `present_at`.
+fn queue_frame(led_state: &LedState, number: u32, present_at: Option<embassy_time::Instant>) {
+    {
+        // Both outputs switch to the new frame together.
+        let mut locked = LED_MUTEX.each_ref().map(|m| m.lock().unwrap());
+        for (channel, frames) in locked.iter_mut().enumerate() {
+            let leds = led_state.get_channel_leds(channel);
+            match present_at {
+                Some(present_at) => frames.schedule(number, present_at, leds),
+                None => frames.show(number, leds),
+            }
         }
     }
-    pub fn get_leds(&self) -> &[RGB8] {
-        let pixels = self.leds.as_pixels();
-        if let Some(pixel_count) = self.pixel_count {
-            &pixels[..pixel_count.min(pixels.len())]
-        } else {
-            pixels
+    if present_at.is_some() {
+        for queued in &FRAME_QUEUED {
+            queued.signal(());
         }
     }
-    // Returns: whether caller should write LED data out to RMT
-    pub fn on_message(&mut self, header: Header, mut rx_packet: &[u8]) -> OnMessageResult {
-        let mut pong_data = None;
-
-        if header.frame_offset == 0 {
-            // Reset framing state.
-            self.reset();
-
-            let msg_type = rx_packet[0];
-            rx_packet = &rx_packet[1..];
-            if msg_type == MessageType::BrainIdRequest as u8 {
-                return OnMessageResult {
-                    pong_data: pong_data,
-                    action: OnMessageAction::SendBrainHello,
-                };
-            }
-            if msg_type == MessageType::UseFirmware as u8 {
-                match proto::UseFirmware::parse(rx_packet) {
-                    Ok(use_firmware) => {
-                        return OnMessageResult {
-                            action: OnMessageAction::DownloadFirmware(use_firmware.url),
-                            pong_data: None,
-                        };
-                    }
-                    Err(e) => {
-                        error!("Failed to parse UseFirmware message {e:?}");
-                        return OnMessageResult {
-                            action: OnMessageAction::Nothing,
-                            pong_data: None,
-                        };
-                    }
-                }
-            }
-            if msg_type != MessageType::BrainPanelShade as u8 {
-                info!("got unsupported message type {msg_type}");
-                return OnMessageResult {
-                    pong_data: pong_data,
-                    action: OnMessageAction::Nothing,
-                };
-            }
+}
 
-            /*
-             * Brain Panel Shade message format:
-             * 12byte header | 0x01 (message type) | 1byte bool hasPongData | optional bytearray (int + bytes) |
-             * | bytearray shader descrption  (2-bytes: 0x01 (PIXEL type),  0x01 (encoding RGB))
-             */
-            let has_pong = rx_packet[0] > 0;
-            rx_packet = &rx_packet[1..];
-            if has_pong {
-                let pong_len = i32::from_be_bytes(rx_packet[..4].try_into().unwrap());
-                rx_packet = &rx_packet[4..];
-                info!("got pong data");
-                if let Ok(pong_data_buf) =
-                    heapless::Vec::from_slice(&rx_packet[..pong_len as usize])
-                {
-                    pong_data = Some(pong_data_buf);
-                } else {
-                    error!("Pong data of length {pong_len} too long, max is {PONG_DATA_MAX}");
-                }
-                rx_packet = &rx_packet[pong_len as usize..];
-            }
-            let desc_len = i32::from_be_bytes(rx_packet[..4].try_into().unwrap());
-            rx_packet = &rx_packet[4..];
-            let desc = &rx_packet[..desc_len as usize];
-            rx_packet = &rx_packet[desc_len as usize..];
-
-            let pixel_count = u16::from_be_bytes(rx_packet[..2].try_into().unwrap());
-            rx_packet = &rx_packet[2..];
-            self.pixel_count = Some(pixel_count as usize);
-
-            match desc {
-                &[1, 2] => {
-                    // ARGB, but we ignore A
-                    let palette_len = 2 * 4;
-                    // Indexed palette of 2 colors
-                    let palette = rx_packet[..palette_len].to_vec();
-                    rx_packet = &rx_packet[palette_len..];
-                    self.palette = Some(palette);
-                }
-                &[1, 1] => {
-                    self.palette = None;
-                }
-                _ => {
-                    //TODO: support mapping descriptor [1, 2]
-                    info!("unsupported descriptor {:x?}", desc);
-                    // Only pixel shader currently supported
-                    self.reset();
-                    return OnMessageResult {
-                        pong_data: pong_data,
-                        action: OnMessageAction::Nothing,
-                    };
-                }
-            }
-        } else {
-            if let Some(last_header) = &self.last_header
-                && last_header.id == header.id
-                && (last_header.frame_offset as usize) + (last_header.frame_size as usize)
-                    == header.frame_offset as usize
-            {
-                // Direct continuation of last packet, fall through to writing LED data.
-            } else {
-                // Not a continuation. Regardless of whether this is a network
-                // error or we actually finished the last message, reset state.
-                log::debug!(
-                    "noncontiguous frame received, resetting {:?} {:?}",
-                    &self.last_header,
-                    &header
-                );
-                self.reset();
-                return OnMessageResult {
-                    pong_data: pong_data,
-                    action: OnMessageAction::Nothing,
-                };
-            }
-        }
+/// BrainHello payload for this brain.
+fn brain_hello(
+    brain_id: &str,
+    panel_name: Option<&str>,
+    firmware_version: Option<&str>,
+) -> heapless::Vec<u8, FRAGMENT_MAX> {
+    BrainHello {
+        brain_id,
+        panel_name,
+        firmware_version,
+        idf_version: ota::running_idf_version(),
+        capabilities: Some(capabilities()),
+    }
+    .to_heapless()
+}
 
-        let offset = self.last_led_byte_idx.unwrap_or(0);
-        if let Some(ref palette) = self.palette {
-            // TODO: handle out of bounds inputs (more pixels than we store)
-            // NOTE: only 2 palette supported
-            for (i, b) in rx_packet.iter().enumerate() {
-                for bit in 0..8 {
-                    let led_index = (offset + i) * 8 + bit;
-                    if led_index * 3 >= self.leds.len() {
-                        continue;
-                    }
-                    let pixel = &mut self.leds[led_index * 3..(led_index + 1) * 3];
-                    if (1 << (8 - bit - 1)) & b == 0 {
-                        pixel.copy_from_slice(&palette[1..4]);
-                    } else {
-                        pixel.copy_from_slice(&palette[5..8]);
-                    }
-                }
-            }
+/// Telemetry for pinky, from the counters kept by main_task, `led_state`
+/// and the led_write_tasks.
+fn telemetry(
+    led_state: &LedState,
+    frames_received: u32,
+    malformed_frames: u32,
+    reset_reason: u8,
+    reboot_reason: Option<heapless::String<REBOOT_REASON_MAX>>,
+    network_if: &impl NetworkInterface,
+) -> Telemetry {
+    Telemetry {
+        uptime_ms: embassy_time::Instant::now().as_millis(),
+        frames_received,
+        frames_rendered: FRAMES_RENDERED.load(Ordering::Relaxed),
+        frames_dropped: malformed_frames.wrapping_add(led_state.stale_frames()),
+        fragment_resets: led_state.incomplete_messages(),
+        late_frames: LATE_FRAMES.load(Ordering::Relaxed),
+        missed_deltas: led_state.missed_deltas(),
+        free_heap: unsafe { esp_get_free_heap_size() },
+        min_free_heap: unsafe { esp_get_minimum_free_heap_size() },
+        reset_reason,
+        link: network_if.link_type(),
+        ip: network_if.get_ip().octets(),
+        reboot_reason,
+    }
+}
 
-            self.last_led_byte_idx = Some(offset + rx_packet.len());
-        } else {
-            // dbg!(offset, rx_packet.len(), self.leds.len());
-            let leds_to_copy = if offset + rx_packet.len() >= self.leds.len() {
-                self.leds.len().saturating_sub(offset)
-            } else {
-                rx_packet.len()
-            };
-            self.leds.as_mut_slice()[offset..offset + leds_to_copy]
-                .copy_from_slice(&rx_packet[..leds_to_copy]);
+/// What this firmware can render and do, so pinky only sends what we support.
+fn capabilities() -> Capabilities {
+    let mut features = Features::MAPPING
+        | Features::PONG_TIMESTAMPS
+        | Features::SCHEDULED_FRAMES
+        | Features::CONFIGURE
+        | Features::IDENTIFY
+        | Features::REBOOT;
+    if option_env!("NO_OTA").is_none() {
+        features = features | Features::OTA;
+    }
+    Capabilities {
+        protocol_version: PROTOCOL_VERSION,
+        encodings: Encoding::mask(&[
+            Encoding::DirectArgb,
+            Encoding::DirectRgb,
+            Encoding::Indexed2,
+            Encoding::Indexed4,
+            Encoding::Indexed16,
+            Encoding::RleRgb,
+            Encoding::Delta,
+        ]),
+        max_leds: MAX_LEDS as u32,
+        channels: CHANNELS as u8,
+        features,
+    }
+}
 
-            self.last_led_byte_idx = Some(offset + leds_to_copy);
-        }
+/// Sends `payload` as message `msg_id`, split into as many frames as needed.
+async fn send_msg(
+    udp_sock: &Async<UdpSocket>,
+    msg_id: i16,
+    payload: &[u8],
+    to: SocketAddr,
+) -> std::io::Result<()> {
+    for frame in prepend_header_heapless(msg_id, payload) {
+        udp_sock.send_to(&frame, to).await?;
+    }
+    Ok(())
+}
 
-        let ready_to_write = header.frame_offset + header.frame_size as i32 == header.msg_size;
-        self.last_header = Some(header);
-        OnMessageResult {
-            pong_data,
-            action: if ready_to_write {
-                OnMessageAction::WriteLeds
-            } else {
-                OnMessageAction::Nothing
-            },
-        }
+/// Sends `payload` wherever pinky may be listening for brains: the subnet
+/// broadcast, and the multicast group if we're in one, as some APs drop or
+/// rate limit broadcast. Both copies share a message id.
+async fn send_to_pinky(
+    udp_sock: &Async<UdpSocket>,
+    msg_id: i16,
+    payload: &[u8],
+    bcast_addr: Ipv4Addr,
+    multicast_group: Option<Ipv4Addr>,
+) -> std::io::Result<()> {
+    if let Some(group) = multicast_group
+        && let Err(e) = send_msg(udp_sock, msg_id, payload, (group, PINKY_PORT).into()).await
+    {
+        error!("Failed to send to multicast group {group}: {e:?}");
     }
+    send_msg(udp_sock, msg_id, payload, (bcast_addr, PINKY_PORT).into()).await
+}
+
+/// Joins `group` on the interface with address `ip`, sending an IGMP report
+/// so switches forward the group to us.
+fn join_multicast_group(udp_sock: &Async<UdpSocket>, group: Ipv4Addr, ip: Ipv4Addr) {
+    match udp_sock.get_ref().join_multicast_v4(&group, &ip) {
+        Ok(()) => info!("Joined multicast group {group}"),
+        Err(e) => error!("Failed to join multicast group {group}: {e:?}"),
+    }
+}
 
-    fn reset(&mut self) {
-        self.last_header = None;
-        self.last_led_byte_idx = None;
-        self.palette = None;
+fn leave_multicast_group(udp_sock: &Async<UdpSocket>, group: Ipv4Addr, ip: Ipv4Addr) {
+    match udp_sock.get_ref().leave_multicast_v4(&group, &ip) {
+        Ok(()) => info!("Left multicast group {group}"),
+        Err(e) => error!("Failed to leave multicast group {group}: {e:?}"),
     }
 }
@@ -8,8 +8,11 @@ use crate::*;
 pub async fn connect_eth(
     mut eth: AsyncEth<EspEth<'static, RmiiEth>>,
 ) -> anyhow::Result<AsyncEth<EspEth<'static, RmiiEth>>> {
-    eth.start().await?;
-    info!("Eth started");
+    // Already started when reconnecting after the link went down.
+    if !eth.is_started()? {
+        eth.start().await?;
+        info!("Eth started");
+    }
 
     eth.wait_connected().await?;
     info!("Eth connected");
@@ -33,10 +36,12 @@ pub async fn connect_wifi(
         ..Default::default()
     });
 
-    wifi.set_configuration(&wifi_configuration)?;
-
-    wifi.start().await?;
-    info!("Wifi started");
+    // Already started when reconnecting after the link went down.
+    if !wifi.is_started()? {
+        wifi.set_configuration(&wifi_configuration)?;
+        wifi.start().await?;
+        info!("Wifi started");
+    }
 
     wifi.connect().await?;
     info!("Wifi connected");
@@ -55,6 +60,7 @@ pub trait NetworkInterface: Sized {
         bcast_addr
     }
     fn is_up(&self) -> bool;
+    fn link_type(&self) -> LinkType;
 
     #[allow(async_fn_in_trait)]
     async fn outer_connect(self) -> anyhow::Result<Self>;
@@ -80,6 +86,10 @@ impl NetworkInterface for AsyncWifi<EspWifi<'static>> {
         self.wifi().is_up().unwrap()
     }
 
+    fn link_type(&self) -> LinkType {
+        LinkType::Wifi
+    }
+
     async fn outer_connect(self) -> anyhow::Result<Self> {
         connect_wifi(self).await
     }
@@ -102,6 +112,10 @@ impl NetworkInterface for AsyncEth<EspEth<'static, RmiiEth>> {
         self.eth().is_up().unwrap()
     }
 
+    fn link_type(&self) -> LinkType {
+        LinkType::Ethernet
+    }
+
     async fn outer_connect(self) -> anyhow::Result<Self> {
         connect_eth(self).await
     }
@@ -155,8 +169,8 @@ pub fn setup_wifi_driver(
     modem: Modem,
     sys_loop: &EspSystemEventLoop,
     timer_service: &EspTaskTimerService,
+    nvs: EspDefaultNvsPartition,
 ) -> AsyncWifi<EspWifi<'static>> {
-    let nvs = EspDefaultNvsPartition::take().unwrap();
     let mut wifi = AsyncWifi::wrap(
         EspWifi::new(modem, sys_loop.clone(), Some(nvs)).unwrap(),
         sys_loop.clone(),
@@ -108,6 +108,12 @@ pub fn running_esp_app_version() -> Option<&'static str> {
     cstr.to_str().ok()
 }
 
+/// Version of ESP-IDF the firmware was built against, e.g. "v5.3.2".
+pub fn running_idf_version() -> Option<&'static str> {
+    let cstr = unsafe { CStr::from_ptr(esp_idf_svc::hal::sys::esp_get_idf_version()) };
+    cstr.to_str().ok()
+}
+
 pub fn running_sparklemotion_version() -> Option<heapless::String<32>> {
     let esp_ver = running_esp_app_version()?;
 
@@ -0,0 +1,240 @@
+//! Per-brain settings persisted in NVS, and pixel coordinates in the storage
+//! partition, so they survive a reboot.
+
+use std::ffi::CStr;
+
+use esp_idf_svc::{
+    nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault},
+    sys::{esp, esp_vfs_spiffs_conf_t, esp_vfs_spiffs_register},
+};
+use log::error;
+
+use crate::{
+    led_state::{CHANNELS, ChannelLayout},
+    proto::{ColorOrder, Configure, DmxUniverses, PANEL_NAME_MAX, REBOOT_REASON_MAX},
+};
+
+const NAMESPACE: &str = "brain";
+
+// NVS keys are limited to 15 characters.
+const KEY_PANEL_NAME: &str = "panel_name";
+const KEY_PIXEL_COUNT: &str = "pixel_count";
+const KEY_CHANNEL_LEDS: [&str; CHANNELS] = ["ch1_leds", "ch2_leds"];
+const KEY_MAX_FPS: &str = "max_fps";
+const KEY_COLOR_ORDER: &str = "color_order";
+const KEY_GAMMA: &str = "gamma";
+const KEY_BRIGHTNESS: &str = "brightness";
+const KEY_LIVENESS_TIMEOUT: &str = "liveness_ms";
+const KEY_REBOOT_REASON: &str = "reboot_reason";
+const KEY_MULTICAST_GROUP: &str = "mcast_group";
+/// First universe in the high 16 bits, count in the low.
+const KEY_DMX_UNIVERSES: &str = "dmx_universes";
+
+/// spiffs partition for what doesn't fit in the 16K nvs one.
+const STORAGE_PARTITION: &CStr = c"storage";
+const STORAGE_PATH: &CStr = c"/storage";
+/// Pixel coordinates from the last BrainMapping, x, y and z as big-endian
+/// f32s per pixel. 2048 pixels take 24K.
+const COORDINATES_FILE: &str = "/storage/coordinates";
+
+pub struct Settings {
+    nvs: EspNvs<NvsDefault>,
+}
+
+impl Settings {
+    pub fn new(partition: EspDefaultNvsPartition) -> anyhow::Result<Self> {
+        let storage = esp_vfs_spiffs_conf_t {
+            base_path: STORAGE_PATH.as_ptr(),
+            partition_label: STORAGE_PARTITION.as_ptr(),
+            max_files: 1,
+            format_if_mount_failed: true,
+        };
+        // Stays mounted while the brain runs. Without it only the pixel
+        // coordinates are lost.
+        if let Err(e) = esp!(unsafe { esp_vfs_spiffs_register(&storage) }) {
+            error!("Failed to mount storage {e:?}");
+        }
+        Ok(Self {
+            nvs: EspNvs::new(partition, NAMESPACE, true)?,
+        })
+    }
+
+    /// Panel name assigned by the last BrainMapping.
+    pub fn panel_name(&self) -> Option<heapless::String<PANEL_NAME_MAX>> {
+        let mut buf = [0u8; PANEL_NAME_MAX + 1];
+        match self.nvs.get_str(KEY_PANEL_NAME, &mut buf) {
+            Ok(name) => name.and_then(|name| name.try_into().ok()),
+            Err(e) => {
+                error!("Failed to read panel name {e:?}");
+                None
+            }
+        }
+    }
+
+    /// Pixel count assigned by the last BrainMapping.
+    pub fn pixel_count(&self) -> Option<u32> {
+        match self.nvs.get_u32(KEY_PIXEL_COUNT) {
+            Ok(count) => count,
+            Err(e) => {
+                error!("Failed to read pixel count {e:?}");
+                None
+            }
+        }
+    }
+
+    /// Stores the panel identity and pixel coordinates from a BrainMapping.
+    pub fn set_mapping(
+        &mut self,
+        panel_name: Option<&str>,
+        pixel_count: u32,
+        coordinates: &[[f32; 3]],
+    ) -> anyhow::Result<()> {
+        match panel_name {
+            Some(name) => self.nvs.set_str(KEY_PANEL_NAME, name)?,
+            None => {
+                self.nvs.remove(KEY_PANEL_NAME)?;
+            }
+        }
+        self.nvs.set_u32(KEY_PIXEL_COUNT, pixel_count)?;
+        let bytes: Vec<u8> = coordinates
+            .iter()
+            .flatten()
+            .flat_map(|axis| axis.to_be_bytes())
+            .collect();
+        std::fs::write(COORDINATES_FILE, bytes)?;
+        Ok(())
+    }
+
+    /// LED counts of each output, if they have been configured. Without
+    /// them every LED is on CH1.
+    pub fn channel_layout(&self) -> Option<ChannelLayout> {
+        let mut led_counts = [0; CHANNELS];
+        for (count, key) in led_counts.iter_mut().zip(KEY_CHANNEL_LEDS) {
+            match self.nvs.get_u32(key) {
+                Ok(Some(stored)) => *count = stored as usize,
+                Ok(None) => return None,
+                Err(e) => {
+                    error!("Failed to read {key} {e:?}");
+                    return None;
+                }
+            }
+        }
+        Some(ChannelLayout { led_counts })
+    }
+
+    fn set_channel_layout(&mut self, layout: &ChannelLayout) -> anyhow::Result<()> {
+        for (&count, key) in layout.led_counts.iter().zip(KEY_CHANNEL_LEDS) {
+            self.nvs.set_u32(key, count as u32)?;
+        }
+        Ok(())
+    }
+
+    /// Everything set by Configure messages so far. Fields that never were
+    /// are left out.
+    pub fn config(&self) -> Configure {
+        Configure {
+            led_counts: self
+                .channel_layout()
+                .map(|layout| layout.led_counts.map(|count| count as u16)),
+            max_fps: self.get_u8(KEY_MAX_FPS),
+            color_order: self
+                .get_u8(KEY_COLOR_ORDER)
+                .and_then(|order| ColorOrder::try_from(order).ok()),
+            gamma: self.get_u8(KEY_GAMMA).map(|gamma| gamma != 0),
+            brightness: self.get_u8(KEY_BRIGHTNESS),
+            liveness_timeout_ms: self.get_u32(KEY_LIVENESS_TIMEOUT),
+            multicast_group: self.get_u32(KEY_MULTICAST_GROUP).map(u32::to_be_bytes),
+            dmx_universes: self
+                .get_u32(KEY_DMX_UNIVERSES)
+                .map(|universes| DmxUniverses {
+                    first: (universes >> 16) as u16,
+                    count: universes as u16,
+                }),
+        }
+    }
+
+    /// Stores the fields `configure` sets, keeping the others.
+    pub fn set_config(&mut self, configure: &Configure) -> anyhow::Result<()> {
+        if let Some(led_counts) = configure.led_counts {
+            self.set_channel_layout(&ChannelLayout {
+                led_counts: led_counts.map(usize::from),
+            })?;
+        }
+        for (key, value) in [
+            (KEY_MAX_FPS, configure.max_fps),
+            (
+                KEY_COLOR_ORDER,
+                configure.color_order.map(|order| order as u8),
+            ),
+            (KEY_GAMMA, configure.gamma.map(u8::from)),
+            (KEY_BRIGHTNESS, configure.brightness),
+        ] {
+            if let Some(value) = value {
+                self.nvs.set_u8(key, value)?;
+            }
+        }
+        for (key, value) in [
+            (KEY_LIVENESS_TIMEOUT, configure.liveness_timeout_ms),
+            (
+                KEY_MULTICAST_GROUP,
+                configure.multicast_group.map(u32::from_be_bytes),
+            ),
+            (
+                KEY_DMX_UNIVERSES,
+                configure
+                    .dmx_universes
+                    .map(|universes| ((universes.first as u32) << 16) | universes.count as u32),
+            ),
+        ] {
+            if let Some(value) = value {
+                self.nvs.set_u32(key, value)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Reason of a Reboot about to restart the brain.
+    pub fn set_reboot_reason(&mut self, reason: &str) -> anyhow::Result<()> {
+        self.nvs.set_str(KEY_REBOOT_REASON, reason)?;
+        Ok(())
+    }
+
+    /// Reason stored by the Reboot that restarted the brain, if one did.
+    /// Removed from NVS so a later reset isn't blamed on it.
+    pub fn take_reboot_reason(&mut self) -> Option<heapless::String<REBOOT_REASON_MAX>> {
+        let mut buf = [0u8; REBOOT_REASON_MAX + 1];
+        let reason = match self.nvs.get_str(KEY_REBOOT_REASON, &mut buf) {
+            Ok(reason) => reason.and_then(|reason| reason.try_into().ok()),
+            Err(e) => {
+                error!("Failed to read reboot reason {e:?}");
+                None
+            }
+        };
+        if reason.is_some()
+            && let Err(e) = self.nvs.remove(KEY_REBOOT_REASON)
+        {
+            error!("Failed to clear reboot reason {e:?}");
+        }
+        reason
+    }
+
+    fn get_u8(&self, key: &str) -> Option<u8> {
+        match self.nvs.get_u8(key) {
+            Ok(value) => value,
+            Err(e) => {
+                error!("Failed to read {key} {e:?}");
+                None
+            }
+        }
+    }
+
+    fn get_u32(&self, key: &str) -> Option<u32> {
+        match self.nvs.get_u32(key) {
+            Ok(value) => value,
+            Err(e) => {
+                error!("Failed to read {key} {e:?}");
+                None
+            }
+        }
+    }
+}
@@ -15,7 +15,7 @@
 
 extern crate alloc;
 
-use core::{convert::Infallible, fmt::write, mem::MaybeUninit};
+use core::{fmt::write, mem::MaybeUninit};
 
 use alloc::format;
 use byteorder::{BigEndian, ByteOrder};
@@ -23,7 +23,6 @@ use embassy_executor::Spawner;
 use embassy_futures::select::{select, select3, select4, Either, Either3, Either4};
 use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
 use embassy_time::{Duration, Instant, Ticker, Timer};
-use embedded_io::{ErrorType, Read, Write};
 use esp_backtrace as _;
 use esp_hal::{
     clock::ClockControl,
@@ -59,6 +58,10 @@ use smart_leds::{
     hsv::{hsv2rgb, Hsv},
     RGB, RGB8,
 };
+use sparklemotion_proto::{
+    prepend_header_heapless, sequence::IdCounter, write_hello_msg, Encoding, Header, Message,
+    Shader, VecWriter,
+};
 use static_cell::{make_static, StaticCell};
 
 use embassy_net::{
@@ -276,27 +279,14 @@ async fn main(spawner: Spawner) -> ! {
 
     // let mut msg = heapless::Vec::<u8, 32>::new();
     // let mut msg = [0u8; 32];
-    let mut msg = VecWriter {
-        buffer: Vec::<u8, 128>::new(),
-    };
-    write_hello_msg(&mut msg, &brain_id);
-
-    let mut msg_id = 0i16;
-    let header = Header::from_payload(msg_id, msg.buffer.as_slice());
-    msg_id += 1;
-    let mut msg_with_header = VecWriter {
-        buffer: Vec::<u8, 128>::new(),
-    };
-    msg_with_header
-        .write_all(header.to_bytes().as_slice())
-        .unwrap();
-    msg_with_header.write_all(msg.buffer.as_slice()).unwrap();
-    info!("hello_msg {:x?}", &msg_with_header.buffer);
-
-    socket
-        .send_to(msg_with_header.buffer.as_slice(), broadcast)
-        .await
-        .unwrap();
+    let mut msg = VecWriter::<128>::new();
+    write_hello_msg(&mut msg, &brain_id, None, None);
+
+    let mut msg_ids = IdCounter::default();
+    for frame in prepend_header_heapless(msg_ids.next_id(), &msg.buffer) {
+        info!("hello_msg {:x?}", &frame);
+        socket.send_to(&frame, broadcast).await.unwrap();
+    }
     // loop {
     //     let (len, src) = socket.recv_from(&mut buf).await.unwrap();
     //     trace!("udp rx from {src:?}: {len:} bytes");
@@ -326,34 +316,29 @@ async fn main(spawner: Spawner) -> ! {
         match res {
             Either4::First(r) => {
                 let Ok((recv_len, _)) = r else { continue };
-                let mut reader = &buf[..recv_len];
-                let header = Header::from_reader(&mut reader);
+                let Ok((header, payload)) = Header::decode(&buf[..recv_len]) else {
+                    continue;
+                };
                 if header.frame_offset == 0 {
                     // TODO: handle messages longer than one packet
-                    let msg_type = reader[0];
-                    reader = &reader[1..];
-                    /*
-                     * Brain Panel Shade message format:
-                     * 12byte header | 0x01 (message type) | 1byte bool hasPongData | optional bytearray (int + bytes) |
-                     * | bytearray shader descrption  (2-bytes: 0x01 (PIXEL type),  0x01 (encoding RGB))
-                     */
-                    let has_pong = reader[0] > 0;
-                    reader = &reader[1..];
-                    if has_pong {
-                        let pong_len = i32::from_be_bytes(reader[..4].try_into().unwrap());
-                        reader = &reader[4..];
-                        reader = &reader[pong_len as usize..];
-                    }
-                    let desc_len = i32::from_be_bytes(reader[..4].try_into().unwrap());
-                    reader = &reader[4..];
-                    let desc = &reader[..desc_len as usize];
-                    reader = &reader[desc_len as usize..];
-                    led_parse_state = Some((header.id, reader.len()));
-                    if desc == &[1, 1] {
-                        for (i, [r, b, g]) in reader.array_chunks().enumerate() {
-                            if i < rbg_leds.len() {
-                                rbg_leds[i] = RGB::<u8>::new(*r, *g, *b);
-                            }
+                    let Ok(Message::BrainPanelShade(shade)) = Message::decode(payload) else {
+                        continue;
+                    };
+                    let Ok(Shader::Pixel {
+                        encoding: Encoding::DirectRgb,
+                        pixel_count,
+                        data,
+                        ..
+                    }) = shade.shader()
+                    else {
+                        continue;
+                    };
+                    led_parse_state = Some((header.id, data.len()));
+                    // LEDs past the panel's pixel count are left alone.
+                    let pixels = data.array_chunks().take(pixel_count as usize);
+                    for (i, [r, b, g]) in pixels.enumerate() {
+                        if i < rbg_leds.len() {
+                            rbg_leds[i] = RGB::<u8>::new(*r, *g, *b);
                         }
                     }
                 }
@@ -388,60 +373,6 @@ async fn main(spawner: Spawner) -> ! {
     }
 }
 
-pub struct VecWriter {
-    buffer: Vec<u8, 128>, // Use a heapless Vec with a fixed capacity
-}
-
-impl ErrorType for VecWriter {
-    type Error = Infallible;
-}
-impl Write for VecWriter {
-    fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
-        let available_space = self.buffer.capacity() - self.buffer.len();
-        let write_len = buf.len().min(available_space);
-        self.buffer
-            .extend_from_slice(&buf[..write_len])
-            .map_err(|_| ())
-            .unwrap();
-        Ok(write_len)
-    }
-
-    fn flush(&mut self) -> Result<(), Self::Error> {
-        // No-op for this example
-        Ok(())
-    }
-}
-
-fn write_hello_msg(w: &mut impl Write, brain_id: &str) {
-    /*
-            writeByte(BRAIN_HELLO);
-            writeString(brainId);
-            writeNullableString(panelName);
-            writeNullableString(firmwareVersion);
-            writeNullableString(idfVersion);
-    */
-    w.write_all(&[MessageType::BrainHello as u8]).unwrap();
-    write_str(w, brain_id);
-    write_str_opt(w, None);
-    write_str_opt(w, None);
-    write_str_opt(w, None);
-}
-
-fn write_str(w: &mut impl Write, s: &str) {
-    let len = s.len() as u32;
-    w.write_all(len.to_be_bytes().as_slice()).unwrap();
-    w.write_all(s.as_bytes()).unwrap();
-}
-
-fn write_str_opt(w: &mut impl Write, s: Option<&str>) {
-    if let Some(s) = s {
-        w.write_all(&[1]).unwrap();
-        write_str(w, s);
-    } else {
-        w.write_all(&[0]).unwrap();
-    }
-}
-
 #[embassy_executor::task]
 async fn connection(mut controller: WifiController<'static>) {
     println!("start connection task");
@@ -491,123 +422,6 @@ async fn button_interrupt(
     }
 }
 
-#[repr(u8)]
-#[derive(Copy, Clone)]
-enum MessageType {
-    BrainHello = 0u8,
-    BrainPanelShade = 1u8,
-}
-
-#[derive(Debug, Clone)]
-struct Header {
-    id: i16,
-    frame_size: i16,
-    msg_size: i32,
-    frame_offset: i32,
-}
-
-const FRAGMENT_MAX: usize = 1500;
-const HEADER_SIZE: usize = 12;
-
-impl Header {
-    fn from_payload(id: i16, msg: &[u8]) -> Self {
-        // TODO: impl fragmentation
-        Self {
-            id,
-            frame_size: msg.len() as i16,
-            msg_size: msg.len() as i32,
-            frame_offset: 0,
-        }
-    }
-    fn to_bytes(&self) -> [u8; HEADER_SIZE] {
-        let mut buf = [0u8; HEADER_SIZE];
-        let mut w = buf.as_mut_slice();
-        w.write_all(&self.id.to_be_bytes()).unwrap();
-        w.write_all(&self.frame_size.to_be_bytes()).unwrap();
-        w.write_all(&self.msg_size.to_be_bytes()).unwrap();
-        w.write_all(&self.frame_offset.to_be_bytes()).unwrap();
-        buf
-    }
-    fn from_reader(r: &mut impl Read) -> Self {
-        let mut id_bytes = [0u8; 2];
-        r.read_exact(&mut id_bytes).unwrap();
-        let mut frame_size = [0u8; 2];
-        r.read_exact(&mut frame_size).unwrap();
-        let mut msg_size = [0u8; size_of::<i32>()];
-        r.read_exact(&mut msg_size).unwrap();
-        let mut frame_offset = [0u8; size_of::<i32>()];
-        r.read_exact(&mut frame_offset).unwrap();
-        Self {
-            id: i16::from_be_bytes(id_bytes),
-            frame_size: i16::from_be_bytes(frame_size),
-            msg_size: i32::from_be_bytes(msg_size),
-            frame_offset: i32::from_be_bytes(frame_offset),
-        }
-    }
-}
-
-/*
-    enum class Type : uint8_t {
-        BRAIN_HELLO,       // Brain -> Pinky|Mapper
-        BRAIN_PANEL_SHADE, // Pinky -> Brain
-        MAPPER_HELLO,      // Mapper -> Pinky
-        BRAIN_ID_REQUEST,  // Mapper -> Brain
-        BRAIN_MAPPING,
-        PING,
-        USE_FIRMWARE,
-    };
-
-    static const size_t FRAGMENT_MAX = 1500;
-    static const size_t HEADER_SIZE = 12;
-
-    struct Header {
-        int16_t id;
-        int16_t frameSize;
-        int32_t msgSize;
-        int32_t frameOffset;
-    };
-
-    BrainHelloMsg(const char *brainId,
-            const char *panelName,
-            const char *firmwareVersion,
-            const char *idfVersion) {
-        // Need capacity for:
-        //      id byte
-        //      brainId string
-        //      panelName NullableString (adds 1 byte boolean)
-        //      firmwareVersion string
-        //      idfVersion string
-        if (prepCapacity(
-                1 +
-                capFor(brainId) +
-                capForNullable(panelName) +
-                capForNullable(firmwareVersion) +
-                capForNullable(idfVersion)
-                )) {
-
-            writeByte(static_cast<int>(Msg::Type::BRAIN_HELLO));
-            writeString(brainId);
-            writeNullableString(panelName);
-            writeNullableString(firmwareVersion);
-            writeNullableString(idfVersion);
-        }
-    }
-
-    void writeString(const char* sz) {
-        if (!sz) return;
-
-        size_t len = strlen(sz);
-        size_t xtra = capFor(sz);
-        if (prepCapacity(m_used + xtra)) {
-            writeInt(len);
-            for ( int i = 0; i < len; i++ ) {
-                m_buf[m_cursor++] = (uint8_t)sz[i];
-            }
-            if (m_cursor > m_used) m_used = m_cursor;
-        }
-    }
-*/
-
 #[global_allocator]
 static ALLOCATOR: esp_alloc::EspHeap = esp_alloc::EspHeap::empty();
 
@@ -623,22 +437,3 @@ fn init_heap() {
 async fn ap_task(stack: &'static Stack<WifiDevice<'static, WifiApDevice>>) {
     stack.run().await
 }
-
-/*
-    enum class Encoding : uint8_t {
-        DIRECT_ARGB = 0,
-        DIRECT_RGB,
-        INDEXED_2,
-        INDEXED_4,
-        INDEXED_16
-    };
-*/
-
-#[repr(u8)]
-enum PixelShaderEncoding {
-    DirectArgb = 0,
-    DirectRgb,
-    Indexed2,
-    Indexed4,
-    Indexed16,
-}
@@ -1,4 +1,93 @@
-use std::net::UdpSocket;
+use std::{
+    collections::HashMap,
+    net::{SocketAddr, UdpSocket},
+    ops::Range,
+};
+
+use sparklemotion_proto::{Header, Message, FRAGMENT_MAX};
+
+/// Most messages reassembled at once. Past this the ones missing frames are
+/// forgotten.
+const MAX_PARTIALS: usize = 64;
+/// Largest message buffered; brains send nothing near this big.
+const MESSAGE_MAX: usize = 64 * 1024;
+
+/// A message from a brain that is still missing frames.
+struct Partial {
+    buf: Vec<u8>,
+    /// Sorted, non-overlapping byte ranges of `buf` received so far, so
+    /// duplicate and overlapping frames aren't counted twice.
+    received: Vec<Range<usize>>,
+}
+
+impl Partial {
+    fn new(msg_size: usize) -> Self {
+        Partial {
+            buf: vec![0; msg_size],
+            received: Vec::new(),
+        }
+    }
+
+    fn insert(&mut self, mut range: Range<usize>) {
+        // Merge with any ranges it overlaps or touches.
+        self.received.retain(|r| {
+            if r.start <= range.end && range.start <= r.end {
+                range.start = range.start.min(r.start);
+                range.end = range.end.max(r.end);
+                false
+            } else {
+                true
+            }
+        });
+        let idx = self.received.partition_point(|r| r.start < range.start);
+        self.received.insert(idx, range);
+    }
+
+    fn is_complete(&self) -> bool {
+        self.received.len() == 1 && self.received[0] == (0..self.buf.len())
+    }
+}
+
+/// Adds one frame, returning the whole message once every frame has arrived.
+fn reassemble(
+    partials: &mut HashMap<(SocketAddr, i16), Partial>,
+    src: SocketAddr,
+    header: &Header,
+    payload: &[u8],
+) -> Option<Vec<u8>> {
+    let msg_size = header.msg_size as usize;
+    if header.frame_offset == 0 && payload.len() == msg_size {
+        return Some(payload.to_vec());
+    }
+    if msg_size > MESSAGE_MAX {
+        println!(
+            "Dropping frame of {msg_size} byte message {} from {src}",
+            header.id
+        );
+        return None;
+    }
+    if partials.len() >= MAX_PARTIALS {
+        println!("Dropping {} incomplete messages", partials.len());
+        partials.clear();
+    }
+    let key = (src, header.id);
+    let partial = partials
+        .entry(key)
+        .or_insert_with(|| Partial::new(msg_size));
+    // Same id but a different size means the brain's id wrapped around onto
+    // a message we never finished.
+    if partial.buf.len() != msg_size {
+        *partial = Partial::new(msg_size);
+    }
+    let start = header.frame_offset as usize;
+    let range = start..start + payload.len();
+    partial.buf[range.clone()].copy_from_slice(payload);
+    partial.insert(range);
+    if !partial.is_complete() {
+        return None;
+    }
+    partials.remove(&key).map(|partial| partial.buf)
+}
 
 fn main() -> std::io::Result<()> {
     // Bind the UDP socket to the desired port
@@ -6,12 +95,53 @@ fn main() -> std::io::Result<()> {
     socket.set_broadcast(true).unwrap();
     println!("Listening on UDP port 8002...");
 
-    let mut buf = [0; 1024]; // Buffer to store incoming data
+    let mut buf = [0; FRAGMENT_MAX]; // Buffer to store incoming data
+
+    // Large BrainHellos and Telemetry are split over several frames.
+    let mut partials = HashMap::new();
 
     loop {
         // Receive data from the socket
         let (amt, src) = socket.recv_from(&mut buf)?;
 
+        let decoded = match Header::decode(&buf[..amt]) {
+            Ok((header, payload)) => {
+                let Some(msg) = reassemble(&mut partials, src, &header, payload) else {
+                    continue;
+                };
+                Message::decode(&msg).map(|msg| match msg {
+                    Message::BrainHello(hello) => {
+                        println!(
+                            "BrainHello {} from {src}: brain {} panel {:?} firmware {:?} idf {:?}",
+                            header.id,
+                            hello.brain_id,
+                            hello.panel_name,
+                            hello.firmware_version,
+                            hello.idf_version
+                        );
+                        if let Some(capabilities) = hello.capabilities {
+                            println!("  {capabilities:?}");
+                        }
+                        true
+                    }
+                    Message::Telemetry(telemetry) => {
+                        println!("Telemetry {} from {src}: {telemetry:?}", header.id);
+                        true
+                    }
+                    msg => {
+                        println!("{:?} {} from {src}", msg.message_type(), header.id);
+                        false
+                    }
+                })
+            }
+            Err(e) => Err(e),
+        };
+        match decoded {
+            Ok(true) => continue,
+            Ok(false) => {}
+            Err(e) => println!("Couldn't decode packet from {src}: {e}"),
+        }
+
         // Convert the data to hexadecimal format
         let hex_data: String = buf[..amt]
             .iter()
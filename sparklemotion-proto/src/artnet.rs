@@ -0,0 +1,455 @@
+//! Art-Net 4 node: answers ArtPoll so consoles find a brain, and takes
+//! pixels from ArtDmx for the configured universes, 170 RGB pixels each as
+//! over E1.31. Consoles number Art-Net universes from 0 and E1.31 ones from
+//! 1, so E1.31 universe 1 is Port-Address 0, and universes past the last
+//! Port-Address are only reachable over E1.31.
+
+use alloc::{format, vec, vec::Vec};
+use core::{net::IpAddr, ops::Range};
+
+use crate::{
+    DmxUniverses, ProtoError, Reader,
+    e131::{PIXELS_PER_UNIVERSE, is_late},
+};
+
+pub const ARTNET_PORT: u16 = 6454;
+pub const POLL_REPLY_SIZE: usize = 239;
+/// Port-Addresses are 15 bits.
+pub const MAX_PORT_ADDRESS: u16 = 0x7fff;
+
+const ID: [u8; 8] = *b"Art-Net\0";
+const PROTOCOL_VERSION: u16 = 14;
+const OP_POLL: u16 = 0x2000;
+const OP_POLL_REPLY: u16 = 0x2100;
+const OP_DMX: u16 = 0x5000;
+const OP_SYNC: u16 = 0x5200;
+/// Ports described by one ArtPollReply.
+const PORTS_PER_REPLY: usize = 4;
+/// ArtDmx is shown as it comes once this long passes without an ArtSync.
+const SYNC_TIMEOUT_US: u64 = 4_000_000;
+/// ESTA manufacturer code reserved for prototypes.
+const ESTA_PROTOTYPE: u16 = 0x7ff0;
+const OEM_UNKNOWN: u16 = 0x00ff;
+/// Indicators normal, Port-Address set over the network.
+const STATUS1: u8 = 0b1110_0000;
+/// Supports 15 bit Port-Addresses.
+const STATUS2: u8 = 0b0000_1000;
+/// Output port of DMX512 data.
+const PORT_TYPE_OUTPUT: u8 = 0x80;
+/// Output port is sending data.
+const GOOD_OUTPUT_SENDING: u8 = 0x80;
+
+/// What the brain tells consoles about itself in ArtPollReply.
+pub struct NodeInfo<'a> {
+    pub ip: [u8; 4],
+    pub mac: [u8; 6],
+    /// Truncated to 17 bytes.
+    pub short_name: &'a str,
+    /// Truncated to 63 bytes.
+    pub long_name: &'a str,
+}
+
+pub enum ArtNetAction {
+    Nothing,
+    /// Broadcast [`ArtNetReceiver::poll_replies`] to port 6454.
+    ReplyToPoll,
+    /// Pixels were set, show the frame.
+    ShowFrame,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Packet<'a> {
+    Poll,
+    Dmx {
+        sequence: u8,
+        port_address: u16,
+        data: &'a [u8],
+    },
+    Sync,
+}
+
+impl<'a> Packet<'a> {
+    /// Returns None for valid packets of other kinds.
+    fn decode(packet: &'a [u8]) -> Result<Option<Self>, ProtoError> {
+        let mut r = Reader::new(packet);
+        if r.read_array()? != ID {
+            return Ok(None);
+        }
+        let op_code = u16::from_le_bytes(r.read_array()?);
+        if r.read_u16()? < PROTOCOL_VERSION {
+            return Ok(None);
+        }
+        Ok(match op_code {
+            OP_POLL => Some(Packet::Poll),
+            OP_DMX => {
+                let sequence = r.read_u8()?;
+                let _physical = r.read_u8()?;
+                let port_address = u16::from_le_bytes(r.read_array()?) & MAX_PORT_ADDRESS;
+                let len = r.read_u16()?;
+                Some(Packet::Dmx {
+                    sequence,
+                    port_address,
+                    data: r.read_exact(len as usize)?,
+                })
+            }
+            OP_SYNC => Some(Packet::Sync),
+            _ => None,
+        })
+    }
+}
+
+#[derive(Default)]
+struct Universe {
+    /// Sequence of the last ArtDmx, 0 when the sender doesn't number them.
+    sequence: u8,
+    /// Data held for the next ArtSync, and who sent it.
+    pending: Option<(IpAddr, Vec<u8>)>,
+    /// Whether ArtDmx has arrived, for GoodOutput.
+    receiving: bool,
+}
+
+pub struct ArtNetReceiver {
+    /// Port-Address of the first universe.
+    first: u16,
+    universes: Vec<Universe>,
+    /// Sender of the last ArtSync followed, and when it arrived.
+    last_sync: Option<(IpAddr, u64)>,
+    polls: u32,
+}
+
+impl ArtNetReceiver {
+    /// Takes the E1.31 universe numbers of `universes`.
+    pub fn new(universes: DmxUniverses) -> Self {
+        let first = universes.first.saturating_sub(1);
+        let count = universes
+            .count
+            .min((MAX_PORT_ADDRESS + 1).saturating_sub(first));
+        Self {
+            first,
+            universes: (0..count).map(|_| Universe::default()).collect(),
+            last_sync: None,
+            polls: 0,
+        }
+    }
+
+    /// Handles one packet from `from` that arrived on the Art-Net socket at
+    /// `now_us`, passing ArtDmx data to `set_pixels` along with the pixel it
+    /// starts at.
+    pub fn on_packet(
+        &mut self,
+        packet: &[u8],
+        from: IpAddr,
+        now_us: u64,
+        mut set_pixels: impl FnMut(usize, &[u8]),
+    ) -> ArtNetAction {
+        match Packet::decode(packet) {
+            Ok(Some(Packet::Poll)) => {
+                self.polls = self.polls.wrapping_add(1);
+                ArtNetAction::ReplyToPoll
+            }
+            Ok(Some(Packet::Dmx {
+                sequence,
+                port_address,
+                data,
+            })) => {
+                let Some(index) = port_address
+                    .checked_sub(self.first)
+                    .map(usize::from)
+                    .filter(|&index| index < self.universes.len())
+                else {
+                    return ArtNetAction::Nothing;
+                };
+                let synced = self.sync_sender(now_us).is_some_and(|sync| sync == from);
+                let universe = &mut self.universes[index];
+                // 0 means the sender doesn't number them.
+                if sequence != 0 && universe.sequence != 0 && is_late(universe.sequence, sequence) {
+                    return ArtNetAction::Nothing;
+                }
+                universe.sequence = sequence;
+                universe.receiving = true;
+                if synced {
+                    let pending = universe.pending.get_or_insert_with(|| (from, Vec::new()));
+                    pending.0 = from;
+                    pending.1.clear();
+                    pending.1.extend_from_slice(data);
+                    ArtNetAction::Nothing
+                } else {
+                    universe.pending = None;
+                    set_pixels(index * PIXELS_PER_UNIVERSE, data);
+                    ArtNetAction::ShowFrame
+                }
+            }
+            Ok(Some(Packet::Sync)) => {
+                // Only the controller whose ArtDmx is held can release it.
+                if self.sync_sender(now_us).is_some_and(|sync| sync != from) {
+                    return ArtNetAction::Nothing;
+                }
+                self.last_sync = Some((from, now_us));
+                let mut changed = false;
+                for (index, universe) in self.universes.iter_mut().enumerate() {
+                    if let Some((_, data)) = universe.pending.take_if(|(sender, _)| *sender == from)
+                    {
+                        set_pixels(index * PIXELS_PER_UNIVERSE, &data);
+                        changed = true;
+                    }
+                }
+                if changed {
+                    ArtNetAction::ShowFrame
+                } else {
+                    ArtNetAction::Nothing
+                }
+            }
+            Ok(None) | Err(_) => ArtNetAction::Nothing,
+        }
+    }
+
+    /// Who is sending ArtSync, if anyone has lately.
+    fn sync_sender(&self, now_us: u64) -> Option<IpAddr> {
+        self.last_sync
+            .filter(|(_, at)| now_us.saturating_sub(*at) < SYNC_TIMEOUT_US)
+            .map(|(sender, _)| sender)
+    }
+
+    /// ArtPollReplies describing the node. Each covers up to four universes
+    /// that share a Net and Sub-Net, numbered by BindIndex from 1.
+    pub fn poll_replies(&self, node: &NodeInfo) -> Vec<[u8; POLL_REPLY_SIZE]> {
+        let mut replies = vec![];
+        let mut index = 0;
+        while index < self.universes.len() {
+            let first = self.first + index as u16;
+            // Ports of a reply only differ in the low 4 bits.
+            let ports = PORTS_PER_REPLY
+                .min(self.universes.len() - index)
+                .min(16 - (first & 0xf) as usize);
+            let bind_index = replies.len() + 1;
+            replies.push(self.poll_reply(node, first, index..index + ports, bind_index as u8));
+            index += ports;
+        }
+        replies
+    }
+
+    fn poll_reply(
+        &self,
+        node: &NodeInfo,
+        first: u16,
+        universes: Range<usize>,
+        bind_index: u8,
+    ) -> [u8; POLL_REPLY_SIZE] {
+        let mut reply = [0u8; POLL_REPLY_SIZE];
+        reply[..8].copy_from_slice(&ID);
+        reply[8..10].copy_from_slice(&OP_POLL_REPLY.to_le_bytes());
+        reply[10..14].copy_from_slice(&node.ip);
+        reply[14..16].copy_from_slice(&ARTNET_PORT.to_le_bytes());
+        reply[18] = (first >> 8) as u8 & 0x7f;
+        reply[19] = (first >> 4) as u8 & 0xf;
+        reply[20..22].copy_from_slice(&OEM_UNKNOWN.to_be_bytes());
+        reply[23] = STATUS1;
+        reply[24..26].copy_from_slice(&ESTA_PROTOTYPE.to_le_bytes());
+        copy_name(&mut reply[26..44], node.short_name);
+        copy_name(&mut reply[44..108], node.long_name);
+        let report = format!("#0001 [{:04}] Brain OK", self.polls % 10000);
+        copy_name(&mut reply[108..172], &report);
+        reply[173] = universes.len() as u8;
+        for (port, index) in universes.enumerate() {
+            reply[174 + port] = PORT_TYPE_OUTPUT;
+            if self.universes[index].receiving {
+                reply[182 + port] = GOOD_OUTPUT_SENDING;
+            }
+            reply[190 + port] = (first + port as u16) as u8 & 0xf;
+        }
+        reply[201..207].copy_from_slice(&node.mac);
+        reply[207..211].copy_from_slice(&node.ip);
+        reply[211] = bind_index;
+        reply[212] = STATUS2;
+        reply
+    }
+}
+
+/// Copies `name` into a null terminated field, cut to fit.
+fn copy_name(field: &mut [u8], name: &str) {
+    let len = name.len().min(field.len() - 1);
+    field[..len].copy_from_slice(&name.as_bytes()[..len]);
+}
+
+#[cfg(test)]
+mod tests {
+    use core::net::Ipv4Addr;
+
+    use super::*;
+
+    const CONSOLE: IpAddr = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 10));
+    const MEDIA_SERVER: IpAddr = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 11));
+
+    fn header(op_code: u16) -> Vec<u8> {
+        let mut p = ID.to_vec();
+        p.extend_from_slice(&op_code.to_le_bytes());
+        p.extend_from_slice(&PROTOCOL_VERSION.to_be_bytes());
+        p
+    }
+
+    fn poll() -> Vec<u8> {
+        let mut p = header(OP_POLL);
+        // Flags and diagnostics priority.
+        p.extend_from_slice(&[0, 0]);
+        p
+    }
+
+    fn dmx(sequence: u8, port_address: u16, data: &[u8]) -> Vec<u8> {
+        let mut p = header(OP_DMX);
+        p.push(sequence);
+        p.push(0);
+        p.extend_from_slice(&port_address.to_le_bytes());
+        p.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        p.extend_from_slice(data);
+        p
+    }
+
+    fn sync() -> Vec<u8> {
+        let mut p = header(OP_SYNC);
+        p.extend_from_slice(&[0, 0]);
+        p
+    }
+
+    /// Passes `packet` to `receiver`, returning the pixels it set.
+    fn receive(
+        receiver: &mut ArtNetReceiver,
+        from: IpAddr,
+        packet: &[u8],
+        now_us: u64,
+    ) -> Vec<(usize, Vec<u8>)> {
+        let mut set = vec![];
+        receiver.on_packet(packet, from, now_us, |start, rgb| {
+            set.push((start, rgb.to_vec()))
+        });
+        set
+    }
+
+    #[test]
+    fn decodes_packets() {
+        assert_eq!(Packet::decode(&poll()), Ok(Some(Packet::Poll)));
+        assert_eq!(
+            Packet::decode(&dmx(7, 0x1234, &[1, 2, 3])),
+            Ok(Some(Packet::Dmx {
+                sequence: 7,
+                port_address: 0x1234,
+                data: &[1, 2, 3],
+            }))
+        );
+        assert_eq!(Packet::decode(&sync()), Ok(Some(Packet::Sync)));
+        let packet = dmx(7, 0x1234, &[1, 2, 3]);
+        assert!(Packet::decode(&packet[..packet.len() - 1]).is_err());
+        assert_eq!(Packet::decode(b"not an Art-Net packet"), Ok(None));
+        let mut old = poll();
+        old[11] = 13;
+        assert_eq!(Packet::decode(&old), Ok(None));
+    }
+
+    #[test]
+    fn universes_are_numbered_from_zero() {
+        // E1.31 universes 1 and 2.
+        let mut receiver = ArtNetReceiver::new(DmxUniverses { first: 1, count: 2 });
+        assert_eq!(
+            receive(&mut receiver, CONSOLE, &dmx(0, 0, &[1]), 0),
+            [(0, vec![1])]
+        );
+        assert_eq!(
+            receive(&mut receiver, CONSOLE, &dmx(0, 1, &[2]), 0),
+            [(PIXELS_PER_UNIVERSE, vec![2])]
+        );
+        assert_eq!(receive(&mut receiver, CONSOLE, &dmx(0, 2, &[3]), 0), []);
+    }
+
+    #[test]
+    fn late_dmx_is_dropped() {
+        let mut receiver = ArtNetReceiver::new(DmxUniverses { first: 1, count: 1 });
+        assert_eq!(
+            receive(&mut receiver, CONSOLE, &dmx(250, 0, &[1]), 0).len(),
+            1
+        );
+        assert_eq!(
+            receive(&mut receiver, CONSOLE, &dmx(240, 0, &[1]), 0).len(),
+            0
+        );
+        assert_eq!(
+            receive(&mut receiver, CONSOLE, &dmx(4, 0, &[1]), 0).len(),
+            1
+        );
+        // 0 means the sender doesn't number them.
+        assert_eq!(
+            receive(&mut receiver, CONSOLE, &dmx(0, 0, &[1]), 0).len(),
+            1
+        );
+        assert_eq!(
+            receive(&mut receiver, CONSOLE, &dmx(0, 0, &[1]), 0).len(),
+            1
+        );
+    }
+
+    #[test]
+    fn sync_releases_held_dmx() {
+        let mut receiver = ArtNetReceiver::new(DmxUniverses { first: 1, count: 2 });
+        assert_eq!(receive(&mut receiver, CONSOLE, &sync(), 0), []);
+        assert_eq!(receive(&mut receiver, CONSOLE, &dmx(1, 0, &[1]), 1), []);
+        assert_eq!(receive(&mut receiver, CONSOLE, &dmx(1, 1, &[2]), 1), []);
+        assert_eq!(
+            receive(&mut receiver, CONSOLE, &sync(), 2),
+            [(0, vec![1]), (PIXELS_PER_UNIVERSE, vec![2])]
+        );
+        let later = 2 + SYNC_TIMEOUT_US;
+        assert_eq!(
+            receive(&mut receiver, CONSOLE, &dmx(2, 0, &[3]), later),
+            [(0, vec![3])]
+        );
+    }
+
+    #[test]
+    fn poll_replies_describe_the_ports() {
+        // Port-Addresses 0x0e to 0x13, across a Sub-Net boundary.
+        let mut receiver = ArtNetReceiver::new(DmxUniverses {
+            first: 15,
+            count: 6,
+        });
+        assert!(matches!(
+            receiver.on_packet(&poll(), CONSOLE, 0, |_, _| {}),
+            ArtNetAction::ReplyToPoll
+        ));
+        receive(&mut receiver, CONSOLE, &dmx(0, 0x0f, &[1]), 0);
+        let node = NodeInfo {
+            ip: [10, 0, 0, 2],
+            mac: [1, 2, 3, 4, 5, 6],
+            short_name: "brain",
+            long_name: "SparkleMotion brain",
+        };
+        let replies = receiver.poll_replies(&node);
+        let [first, second] = &replies[..] else {
+            panic!("{} replies", replies.len())
+        };
+        assert_eq!(&first[..8], &ID);
+        assert_eq!(&first[8..10], &OP_POLL_REPLY.to_le_bytes());
+        assert_eq!(&first[10..14], &node.ip);
+        assert_eq!(&first[26..32], b"brain\0");
+        assert_eq!(&first[108..128], b"#0001 [0001] Brain O");
+        // Net, Sub-Net, then each port's universe.
+        assert_eq!((first[18], first[19], first[173]), (0, 0, 2));
+        assert_eq!(&first[190..192], &[0x0e, 0x0f]);
+        assert_eq!(&first[182..184], &[0, GOOD_OUTPUT_SENDING]);
+        assert_eq!((second[19], second[173]), (1, 4));
+        assert_eq!(&second[190..194], &[0, 1, 2, 3]);
+        assert_eq!((first[211], second[211]), (1, 2));
+    }
+
+    #[test]
+    fn sync_only_from_the_dmx_sender() {
+        let mut receiver = ArtNetReceiver::new(DmxUniverses { first: 1, count: 1 });
+        assert_eq!(receive(&mut receiver, CONSOLE, &sync(), 0), []);
+        assert_eq!(receive(&mut receiver, CONSOLE, &dmx(1, 0, &[1]), 1), []);
+        assert_eq!(receive(&mut receiver, MEDIA_SERVER, &sync(), 2), []);
+        // The media server's ArtDmx isn't held for the console's ArtSync.
+        assert_eq!(
+            receive(&mut receiver, MEDIA_SERVER, &dmx(2, 0, &[2]), 3),
+            [(0, vec![2])]
+        );
+        assert_eq!(receive(&mut receiver, CONSOLE, &dmx(3, 0, &[3]), 4), []);
+        assert_eq!(receive(&mut receiver, CONSOLE, &sync(), 5), [(0, vec![3])]);
+    }
+}
@@ -0,0 +1,370 @@
+//! NTP-style estimate of pinky's clock from a brain's, over Ping messages.
+//!
+//! The brain sends a Ping whose data is [`CLOCK_PING_TAG`] followed by its
+//! clock (t0). Pinky echoes the data in a pong with [`PongTimestamps`] set to
+//! its own clock: `received_us` when the ping arrived (t1) and `displayed_us`
+//! when it sent the pong (t2). With t3, when the pong arrived, each exchange
+//! measures pinky's offset to within the round trip's asymmetry.
+
+use crate::{PONG_DATA_MAX, PongTimestamps};
+
+/// First byte of a clock ping's data, so pongs to other pings are ignored.
+pub const CLOCK_PING_TAG: u8 = b'c';
+
+/// Recent exchanges, the fastest of which sets the offset.
+const RECENT_SAMPLES: usize = 8;
+/// Drift is too slow to see through network jitter over a few exchanges, so
+/// it is fit to the fastest exchange of each batch of this many...
+const BATCH_SAMPLES: usize = 10;
+/// ...over this many batches.
+const DRIFT_SAMPLES: usize = 32;
+/// The batches aren't fit until they span this long.
+const MIN_FIT_SPAN_US: i64 = 10_000_000;
+/// How far an exchange may disagree with the fit, or the fastest recent
+/// exchange before there is one, past what the round trips allow before
+/// pinky's clock is taken to have been set and the estimate dropped.
+const MAX_FIT_ERROR_US: i64 = 1_000;
+
+/// One ping/pong exchange. `sent_us` and `received_us` are on the local
+/// clock, the others on the remote one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClockSample {
+    pub sent_us: u64,
+    pub remote_received_us: u64,
+    pub remote_sent_us: u64,
+    pub received_us: u64,
+}
+
+impl ClockSample {
+    /// Remote clock minus local clock, assuming the network took as long
+    /// each way.
+    pub fn offset_us(&self) -> i64 {
+        let there = self.remote_received_us as i64 - self.sent_us as i64;
+        let back = self.remote_sent_us as i64 - self.received_us as i64;
+        there / 2 + back / 2
+    }
+
+    /// Time spent on the network, both ways.
+    pub fn round_trip_us(&self) -> i64 {
+        (self.received_us as i64 - self.sent_us as i64)
+            - (self.remote_sent_us as i64 - self.remote_received_us as i64)
+    }
+
+    /// Local time halfway through the exchange, when `offset_us` held.
+    fn midpoint_us(&self) -> i64 {
+        self.sent_us as i64 / 2 + self.received_us as i64 / 2
+    }
+}
+
+/// Least squares line through the batches' offsets.
+#[derive(Debug, Clone, Copy)]
+struct Fit {
+    /// A point on the line: local time and the offset then.
+    at_us: i64,
+    offset_us: f64,
+    /// Remote clock rate over ours, minus one.
+    drift: f64,
+}
+
+impl Fit {
+    fn offset_us(&self, local_us: i64) -> i64 {
+        (self.offset_us + (local_us - self.at_us) as f64 * self.drift) as i64
+    }
+}
+
+/// Offset and drift of a remote clock, from a least squares fit over the
+/// fastest exchange of each recent batch. Until there are enough batches, the
+/// offset is the fastest recent exchange's.
+#[derive(Debug, Default)]
+pub struct SyncedClock {
+    recent: heapless::Deque<ClockSample, RECENT_SAMPLES>,
+    batch_best: Option<ClockSample>,
+    batch_len: usize,
+    batches: heapless::Deque<ClockSample, DRIFT_SAMPLES>,
+    fit: Option<Fit>,
+}
+
+impl SyncedClock {
+    /// Data for a clock ping sent at local time `now_us`.
+    pub fn ping_data(now_us: u64) -> heapless::Vec<u8, PONG_DATA_MAX> {
+        let mut data = heapless::Vec::new();
+        data.push(CLOCK_PING_TAG).unwrap();
+        data.extend_from_slice(&now_us.to_be_bytes()).unwrap();
+        data
+    }
+
+    /// Adds the exchange completed by a pong that arrived at local time
+    /// `received_us`. Returns false if it isn't the pong to a clock ping.
+    pub fn on_pong(
+        &mut self,
+        data: &[u8],
+        timestamps: Option<&PongTimestamps>,
+        received_us: u64,
+    ) -> bool {
+        let (Some(timestamps), [CLOCK_PING_TAG, sent @ ..]) = (timestamps, data) else {
+            return false;
+        };
+        let Ok(sent) = <[u8; 8]>::try_from(sent) else {
+            return false;
+        };
+        self.add_sample(ClockSample {
+            sent_us: u64::from_be_bytes(sent),
+            remote_received_us: timestamps.received_us,
+            remote_sent_us: timestamps.displayed_us,
+            received_us,
+        });
+        true
+    }
+
+    pub fn add_sample(&mut self, sample: ClockSample) {
+        // Only timestamps from the wrong clocks go backwards.
+        if sample.round_trip_us() < 0 {
+            return;
+        }
+        // The true offset is within half a round trip of the measured one.
+        let expected = match self.fit {
+            Some(fit) => Some((fit.offset_us(sample.midpoint_us()), 0)),
+            None => self
+                .best()
+                .map(|best| (best.offset_us(), best.round_trip_us() / 2)),
+        };
+        if let Some((offset_us, uncertainty_us)) = expected
+            && (offset_us - sample.offset_us()).abs()
+                > sample.round_trip_us() / 2 + uncertainty_us + MAX_FIT_ERROR_US
+        {
+            *self = Self::default();
+        }
+        if self.recent.is_full() {
+            self.recent.pop_front();
+        }
+        let _ = self.recent.push_back(sample);
+
+        if self
+            .batch_best
+            .is_none_or(|best| sample.round_trip_us() < best.round_trip_us())
+        {
+            self.batch_best = Some(sample);
+        }
+        self.batch_len += 1;
+        if self.batch_len == BATCH_SAMPLES
+            && let Some(best) = self.batch_best.take()
+        {
+            self.batch_len = 0;
+            if self.batches.is_full() {
+                self.batches.pop_front();
+            }
+            let _ = self.batches.push_back(best);
+            self.fit = self.fit_batches();
+        }
+    }
+
+    /// Whether any exchange has completed.
+    pub fn is_synced(&self) -> bool {
+        !self.recent.is_empty()
+    }
+
+    /// Fastest round trip of the recent exchanges.
+    pub fn round_trip_us(&self) -> Option<i64> {
+        self.best().map(ClockSample::round_trip_us)
+    }
+
+    /// Remote clock rate over ours, minus one: positive when it runs fast.
+    pub fn drift(&self) -> f64 {
+        self.fit.map_or(0.0, |fit| fit.drift)
+    }
+
+    /// Remote clock minus local clock at local time `local_us`.
+    pub fn offset_us(&self, local_us: u64) -> Option<i64> {
+        match self.fit {
+            Some(fit) => Some(fit.offset_us(local_us as i64)),
+            None => self.best().map(ClockSample::offset_us),
+        }
+    }
+
+    /// The remote clock at local time `local_us`.
+    pub fn to_remote(&self, local_us: u64) -> Option<u64> {
+        Some(local_us.saturating_add_signed(self.offset_us(local_us)?))
+    }
+
+    /// The local time when the remote clock reads `remote_us`.
+    pub fn to_local(&self, remote_us: u64) -> Option<u64> {
+        // The offset depends on the local time being looked for, but so
+        // little that the offset at a rough guess of it is close enough.
+        let estimate = remote_us.saturating_add_signed(-self.offset_us(remote_us)?);
+        Some(remote_us.saturating_add_signed(-self.offset_us(estimate)?))
+    }
+
+    /// The recent exchange least delayed by the network, so the most
+    /// accurate.
+    fn best(&self) -> Option<&ClockSample> {
+        self.recent.iter().min_by_key(|s| s.round_trip_us())
+    }
+
+    fn fit_batches(&self) -> Option<Fit> {
+        let (first, last) = (self.batches.front()?, self.batches.back()?);
+        if last.midpoint_us() - first.midpoint_us() < MIN_FIT_SPAN_US {
+            return None;
+        }
+
+        // Relative to the first batch, so the sums stay small enough for f64
+        // to hold them exactly.
+        let (mut n, mut sum_x, mut sum_y, mut sum_xx, mut sum_xy) = (0.0, 0.0, 0.0, 0.0, 0.0);
+        for s in &self.batches {
+            let x = (s.midpoint_us() - first.midpoint_us()) as f64;
+            let y = (s.offset_us() - first.offset_us()) as f64;
+            n += 1.0;
+            sum_x += x;
+            sum_y += y;
+            sum_xx += x * x;
+            sum_xy += x * y;
+        }
+        let drift = (n * sum_xy - sum_x * sum_y) / (n * sum_xx - sum_x * sum_x);
+        // The line passes through the mean of the points.
+        Some(Fit {
+            at_us: first.midpoint_us() + (sum_x / n) as i64,
+            offset_us: first.offset_us() as f64 + sum_y / n,
+            drift,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Remote clock running `drift` fast from `offset_us`, behind a network
+    /// whose one-way delay is `base_us` plus up to `jitter_us`.
+    struct Network {
+        offset_us: i64,
+        drift: f64,
+        base_us: u64,
+        jitter_us: u64,
+        rng: u64,
+    }
+
+    impl Network {
+        fn remote(&self, local_us: u64) -> u64 {
+            (local_us as i64 + self.offset_us + (local_us as f64 * self.drift) as i64) as u64
+        }
+
+        fn delay(&mut self) -> u64 {
+            // xorshift64, so runs are repeatable.
+            self.rng ^= self.rng << 13;
+            self.rng ^= self.rng >> 7;
+            self.rng ^= self.rng << 17;
+            self.base_us + self.rng % (self.jitter_us + 1)
+        }
+
+        /// Pings at local `sent_us`, with pinky taking 50us to answer.
+        fn exchange(&mut self, clock: &mut SyncedClock, sent_us: u64) {
+            let data = SyncedClock::ping_data(sent_us);
+            let arrived = sent_us + self.delay();
+            let answered = arrived + 50;
+            let timestamps = PongTimestamps {
+                received_us: self.remote(arrived),
+                reassembled_us: self.remote(arrived),
+                displayed_us: self.remote(answered),
+            };
+            let received_us = answered + self.delay();
+            assert!(clock.on_pong(&data, Some(&timestamps), received_us));
+        }
+    }
+
+    #[test]
+    fn exact_on_a_symmetric_network() {
+        let mut network = Network {
+            offset_us: 1_700_000_000_000_000,
+            drift: 0.0,
+            base_us: 1_000,
+            jitter_us: 0,
+            rng: 1,
+        };
+        let mut clock = SyncedClock::default();
+        assert_eq!(clock.to_local(5), None);
+        network.exchange(&mut clock, 10_000);
+        assert_eq!(clock.offset_us(0), Some(network.offset_us));
+        assert_eq!(clock.round_trip_us(), Some(2_000));
+        assert_eq!(clock.to_local(network.remote(123_456)), Some(123_456));
+        assert_eq!(clock.to_remote(123_456), Some(network.remote(123_456)));
+    }
+
+    #[test]
+    fn tracks_offset_and_drift_through_jitter() {
+        for seed in [0x2545_f491_4f6c_dd1d, 1, 0xdead_beef] {
+            let mut network = Network {
+                offset_us: -3_000_000,
+                drift: 40e-6,
+                base_us: 2_000,
+                jitter_us: 8_000,
+                rng: seed,
+            };
+            let mut clock = SyncedClock::default();
+            for second in 1..=600u64 {
+                network.exchange(&mut clock, second * 1_000_000);
+            }
+            let drift_error = (clock.drift() - network.drift) * 1e6;
+            assert!(
+                -10.0 < drift_error && drift_error < 10.0,
+                "drift off by {drift_error}ppm"
+            );
+            // A few seconds on, the estimate is still well inside the jitter.
+            let now = 605_000_000;
+            let error = clock.to_remote(now).unwrap() as i64 - network.remote(now) as i64;
+            assert!(error.abs() < 1_000, "offset off by {error}us");
+            let error = clock.to_local(network.remote(now)).unwrap() as i64 - now as i64;
+            assert!(error.abs() < 1_000, "local time off by {error}us");
+        }
+    }
+
+    #[test]
+    fn starts_over_when_pinky_clock_is_set() {
+        let mut network = Network {
+            offset_us: 5_000_000,
+            drift: 0.0,
+            base_us: 1_000,
+            jitter_us: 500,
+            rng: 7,
+        };
+        let mut clock = SyncedClock::default();
+        for second in 1..=60u64 {
+            network.exchange(&mut clock, second * 1_000_000);
+        }
+        network.offset_us += 60_000_000;
+        network.exchange(&mut clock, 61_000_000);
+        let error = clock.offset_us(61_000_000).unwrap() - network.offset_us;
+        assert!(error.abs() < 1_000, "offset off by {error}us");
+    }
+
+    #[test]
+    fn starts_over_when_pinky_clock_is_set_before_a_fit() {
+        let mut network = Network {
+            offset_us: 5_000_000,
+            drift: 0.0,
+            base_us: 1_000,
+            jitter_us: 500,
+            rng: 7,
+        };
+        let mut clock = SyncedClock::default();
+        for second in 1..=5u64 {
+            network.exchange(&mut clock, second * 1_000_000);
+        }
+        network.offset_us += 60_000_000;
+        network.exchange(&mut clock, 6_000_000);
+        let error = clock.offset_us(6_000_000).unwrap() - network.offset_us;
+        assert!(error.abs() < 1_000, "offset off by {error}us");
+    }
+
+    #[test]
+    fn ignores_other_pongs() {
+        let mut clock = SyncedClock::default();
+        let timestamps = PongTimestamps {
+            received_us: 1,
+            reassembled_us: 1,
+            displayed_us: 2,
+        };
+        assert!(!clock.on_pong(b"hello", Some(&timestamps), 3));
+        assert!(!clock.on_pong(&SyncedClock::ping_data(0), None, 3));
+        assert!(!clock.on_pong(&[CLOCK_PING_TAG, 1, 2], Some(&timestamps), 3));
+        assert!(!clock.is_synced());
+    }
+}
@@ -0,0 +1,606 @@
+//! E1.31 (streaming ACN) receiver, so lighting consoles and media servers can
+//! drive a brain without pinky. Each universe of the configured range
+//! carries the next 170 RGB pixels of the panel.
+
+use alloc::vec::Vec;
+use core::{net::Ipv4Addr, ops::Range};
+
+use crate::{DmxUniverses, ProtoError, Reader};
+
+pub const E131_PORT: u16 = 5568;
+/// RGB pixels in a 512 slot universe.
+pub const PIXELS_PER_UNIVERSE: usize = 170;
+/// Highest universe number E1.31 allows.
+pub const MAX_UNIVERSE: u16 = 63999;
+
+const ACN_PACKET_IDENTIFIER: [u8; 12] = *b"ASC-E1.17\0\0\0";
+const VECTOR_ROOT_E131_DATA: u32 = 0x0000_0004;
+const VECTOR_ROOT_E131_EXTENDED: u32 = 0x0000_0008;
+const VECTOR_E131_DATA_PACKET: u32 = 0x0000_0002;
+const VECTOR_E131_EXTENDED_SYNCHRONIZATION: u32 = 0x0000_0001;
+const VECTOR_DMP_SET_PROPERTY: u8 = 0x02;
+const OPTION_PREVIEW_DATA: u8 = 1 << 7;
+const OPTION_STREAM_TERMINATED: u8 = 1 << 6;
+/// Start code of dimmer data, others carry something else.
+const DMX_START_CODE: u8 = 0;
+/// A source is gone after this long without a packet, and a sync address
+/// after this long without a sync packet.
+const NETWORK_DATA_LOSS_TIMEOUT_US: u64 = 2_500_000;
+/// Most sync addresses followed at once. Data naming another one is shown as
+/// it comes.
+const MAX_SYNC_ADDRESSES: usize = 4;
+
+/// Whether `sequence` is a late packet after `last`: up to 20 behind it,
+/// counting across the wrap from 255 to 0. Anything else is new.
+pub fn is_late(last: u8, sequence: u8) -> bool {
+    (0..20).contains(&(last.wrapping_sub(sequence) as i8))
+}
+
+/// Multicast group sources send `universe` to.
+pub fn universe_group(universe: u16) -> Ipv4Addr {
+    let [hi, lo] = universe.to_be_bytes();
+    Ipv4Addr::new(239, 255, hi, lo)
+}
+
+/// The packets the receiver acts on; discovery and unknown vectors are left
+/// out.
+#[derive(Debug, PartialEq, Eq)]
+enum Packet<'a> {
+    Data {
+        cid: [u8; 16],
+        priority: u8,
+        sync_address: u16,
+        sequence: u8,
+        options: u8,
+        universe: u16,
+        start_code: u8,
+        slots: &'a [u8],
+    },
+    Sync {
+        cid: [u8; 16],
+        sync_address: u16,
+    },
+}
+
+impl<'a> Packet<'a> {
+    /// Returns None for valid packets of other kinds.
+    fn decode(packet: &'a [u8]) -> Result<Option<Self>, ProtoError> {
+        let mut r = Reader::new(packet);
+        // Preamble and postamble size.
+        r.read_exact(4)?;
+        if r.read_array()? != ACN_PACKET_IDENTIFIER {
+            return Ok(None);
+        }
+        let _flags_length = r.read_u16()?;
+        let root_vector = r.read_u32()?;
+        let cid = r.read_array()?;
+        let _flags_length = r.read_u16()?;
+        let vector = r.read_u32()?;
+        Ok(match (root_vector, vector) {
+            (VECTOR_ROOT_E131_DATA, VECTOR_E131_DATA_PACKET) => {
+                // Source name.
+                r.read_exact(64)?;
+                let priority = r.read_u8()?;
+                let sync_address = r.read_u16()?;
+                let sequence = r.read_u8()?;
+                let options = r.read_u8()?;
+                let universe = r.read_u16()?;
+                let _flags_length = r.read_u16()?;
+                if r.read_u8()? != VECTOR_DMP_SET_PROPERTY {
+                    return Ok(None);
+                }
+                // Address and data type, first property address, address
+                // increment.
+                r.read_exact(5)?;
+                let count = r.read_u16()?;
+                let values = r.read_exact((count as usize).max(1))?;
+                Some(Packet::Data {
+                    cid,
+                    priority,
+                    sync_address,
+                    sequence,
+                    options,
+                    universe,
+                    start_code: values[0],
+                    slots: &values[1..],
+                })
+            }
+            (VECTOR_ROOT_E131_EXTENDED, VECTOR_E131_EXTENDED_SYNCHRONIZATION) => {
+                let _sequence = r.read_u8()?;
+                Some(Packet::Sync {
+                    cid,
+                    sync_address: r.read_u16()?,
+                })
+            }
+            _ => None,
+        })
+    }
+}
+
+/// The source a universe is taken from: the highest priority one heard from
+/// lately, or the first of those with the same priority.
+struct Source {
+    cid: [u8; 16],
+    priority: u8,
+    sequence: u8,
+    last_seen_us: u64,
+}
+
+#[derive(Default)]
+struct Universe {
+    source: Option<Source>,
+    /// Slots held until a sync packet for the address arrives.
+    pending: Option<(u16, Vec<u8>)>,
+}
+
+/// A sync address named by data for one of our universes.
+struct SyncAddress {
+    address: u16,
+    /// Source of the last sync packet, and when it arrived.
+    last_sync: Option<([u8; 16], u64)>,
+}
+
+pub struct E131Receiver {
+    span: DmxUniverses,
+    universes: Vec<Universe>,
+    sync_addresses: Vec<SyncAddress>,
+}
+
+impl E131Receiver {
+    pub fn new(universes: DmxUniverses) -> Self {
+        Self {
+            span: universes,
+            universes: (0..universes.count).map(|_| Universe::default()).collect(),
+            sync_addresses: Vec::new(),
+        }
+    }
+
+    pub fn span(&self) -> DmxUniverses {
+        self.span
+    }
+
+    /// Universe numbers carrying our data.
+    fn universes(&self) -> Range<u16> {
+        self.span.first..self.span.first + self.span.count
+    }
+
+    /// Multicast groups to be in: those of our universes, and of the sync
+    /// addresses our data names.
+    pub fn groups(&self) -> impl Iterator<Item = Ipv4Addr> + '_ {
+        let sync_universes = self
+            .sync_addresses
+            .iter()
+            .map(|sync| sync.address)
+            .filter(|address| !self.universes().contains(address));
+        self.universes().chain(sync_universes).map(universe_group)
+    }
+
+    /// Handles one packet from the E1.31 socket that arrived at `now_us`,
+    /// passing RGB slots to `set_pixels` along with the pixel they start at.
+    /// Returns whether any were, so the frame should be shown. Data naming a
+    /// new sync address outside our universes has its group passed to
+    /// `join_group`, as sources multicast sync packets there.
+    pub fn on_packet(
+        &mut self,
+        packet: &[u8],
+        now_us: u64,
+        mut set_pixels: impl FnMut(usize, &[u8]),
+        mut join_group: impl FnMut(Ipv4Addr),
+    ) -> bool {
+        match Packet::decode(packet) {
+            Ok(Some(Packet::Data {
+                cid,
+                priority,
+                sync_address,
+                sequence,
+                options,
+                universe,
+                start_code,
+                slots,
+            })) => {
+                let Some(index) = universe
+                    .checked_sub(self.span.first)
+                    .map(usize::from)
+                    .filter(|&index| index < self.universes.len())
+                else {
+                    return false;
+                };
+                if options & OPTION_PREVIEW_DATA != 0 || start_code != DMX_START_CODE {
+                    return false;
+                }
+                let state = &mut self.universes[index];
+                match &mut state.source {
+                    Some(source) if source.cid == cid => {
+                        if is_late(source.sequence, sequence) {
+                            return false;
+                        }
+                        if options & OPTION_STREAM_TERMINATED != 0 {
+                            state.source = None;
+                            return false;
+                        }
+                        source.priority = priority;
+                        source.sequence = sequence;
+                        source.last_seen_us = now_us;
+                    }
+                    Some(source)
+                        if now_us.saturating_sub(source.last_seen_us)
+                            < NETWORK_DATA_LOSS_TIMEOUT_US
+                            && priority <= source.priority =>
+                    {
+                        return false;
+                    }
+                    _ => {
+                        if options & OPTION_STREAM_TERMINATED != 0 {
+                            return false;
+                        }
+                        state.source = Some(Source {
+                            cid,
+                            priority,
+                            sequence,
+                            last_seen_us: now_us,
+                        });
+                        state.pending = None;
+                    }
+                }
+                if sync_address != 0
+                    && self.sync_addresses.len() < MAX_SYNC_ADDRESSES
+                    && !self
+                        .sync_addresses
+                        .iter()
+                        .any(|s| s.address == sync_address)
+                {
+                    self.sync_addresses.push(SyncAddress {
+                        address: sync_address,
+                        last_sync: None,
+                    });
+                    let first = self.span.first;
+                    if !(first..first + self.span.count).contains(&sync_address) {
+                        join_group(universe_group(sync_address));
+                    }
+                }
+                // Until the source sends sync packets for the address, or
+                // after it stops, data is shown as it comes.
+                let synced = self.sync_addresses.iter().any(|s| {
+                    s.address == sync_address
+                        && s.last_sync.is_some_and(|(sync_cid, at)| {
+                            sync_cid == cid
+                                && now_us.saturating_sub(at) < NETWORK_DATA_LOSS_TIMEOUT_US
+                        })
+                });
+                if synced {
+                    let pending = state.pending.get_or_insert_default();
+                    pending.0 = sync_address;
+                    pending.1.clear();
+                    pending.1.extend_from_slice(slots);
+                    false
+                } else {
+                    state.pending = None;
+                    set_pixels(index * PIXELS_PER_UNIVERSE, slots);
+                    true
+                }
+            }
+            Ok(Some(Packet::Sync { cid, sync_address })) => {
+                let Some(sync) = self
+                    .sync_addresses
+                    .iter_mut()
+                    .find(|s| s.address == sync_address)
+                else {
+                    return false;
+                };
+                sync.last_sync = Some((cid, now_us));
+                let mut changed = false;
+                // Only the source a universe is taken from can release it.
+                for (index, state) in self.universes.iter_mut().enumerate() {
+                    if state.source.as_ref().is_none_or(|source| source.cid != cid) {
+                        continue;
+                    }
+                    if let Some((_, slots)) = state
+                        .pending
+                        .take_if(|(address, _)| *address == sync_address)
+                    {
+                        set_pixels(index * PIXELS_PER_UNIVERSE, &slots);
+                        changed = true;
+                    }
+                }
+                changed
+            }
+            Ok(None) | Err(_) => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec;
+
+    use super::*;
+
+    const CONSOLE: [u8; 16] = [1; 16];
+    const BACKUP: [u8; 16] = [2; 16];
+
+    fn header(root_vector: u32, cid: [u8; 16], vector: u32) -> Vec<u8> {
+        let mut p = vec![0x00, 0x10, 0x00, 0x00];
+        p.extend_from_slice(&ACN_PACKET_IDENTIFIER);
+        // Flags and lengths aren't checked.
+        p.extend_from_slice(&[0x70, 0x00]);
+        p.extend_from_slice(&root_vector.to_be_bytes());
+        p.extend_from_slice(&cid);
+        p.extend_from_slice(&[0x70, 0x00]);
+        p.extend_from_slice(&vector.to_be_bytes());
+        p
+    }
+
+    struct Data<'a> {
+        cid: [u8; 16],
+        priority: u8,
+        sync_address: u16,
+        sequence: u8,
+        options: u8,
+        universe: u16,
+        slots: &'a [u8],
+    }
+
+    impl Default for Data<'_> {
+        fn default() -> Self {
+            Self {
+                cid: CONSOLE,
+                priority: 100,
+                sync_address: 0,
+                sequence: 1,
+                options: 0,
+                universe: 1,
+                slots: &[1, 2, 3],
+            }
+        }
+    }
+
+    impl Data<'_> {
+        fn encode(&self) -> Vec<u8> {
+            let mut p = header(VECTOR_ROOT_E131_DATA, self.cid, VECTOR_E131_DATA_PACKET);
+            p.extend_from_slice(&[0; 64]);
+            p.push(self.priority);
+            p.extend_from_slice(&self.sync_address.to_be_bytes());
+            p.push(self.sequence);
+            p.push(self.options);
+            p.extend_from_slice(&self.universe.to_be_bytes());
+            p.extend_from_slice(&[0x70, 0x00, VECTOR_DMP_SET_PROPERTY, 0xa1, 0, 0, 0, 1]);
+            p.extend_from_slice(&(self.slots.len() as u16 + 1).to_be_bytes());
+            p.push(DMX_START_CODE);
+            p.extend_from_slice(self.slots);
+            p
+        }
+    }
+
+    fn sync(cid: [u8; 16], sync_address: u16) -> Vec<u8> {
+        let mut p = header(
+            VECTOR_ROOT_E131_EXTENDED,
+            cid,
+            VECTOR_E131_EXTENDED_SYNCHRONIZATION,
+        );
+        p.push(0);
+        p.extend_from_slice(&sync_address.to_be_bytes());
+        p.extend_from_slice(&[0, 0]);
+        p
+    }
+
+    /// Passes `packet` to `receiver`, returning the pixels it set.
+    fn receive(receiver: &mut E131Receiver, packet: &[u8], now_us: u64) -> Vec<(usize, Vec<u8>)> {
+        let mut set = vec![];
+        receiver.on_packet(
+            packet,
+            now_us,
+            |start, rgb| set.push((start, rgb.to_vec())),
+            |_| {},
+        );
+        set
+    }
+
+    fn receiver() -> E131Receiver {
+        E131Receiver::new(DmxUniverses { first: 1, count: 2 })
+    }
+
+    #[test]
+    fn decodes_data() {
+        let data = Data {
+            priority: 150,
+            sync_address: 7,
+            sequence: 9,
+            universe: 3,
+            ..Data::default()
+        };
+        assert_eq!(
+            Packet::decode(&data.encode()),
+            Ok(Some(Packet::Data {
+                cid: CONSOLE,
+                priority: 150,
+                sync_address: 7,
+                sequence: 9,
+                options: 0,
+                universe: 3,
+                start_code: DMX_START_CODE,
+                slots: &[1, 2, 3],
+            }))
+        );
+        assert_eq!(
+            Packet::decode(&sync(CONSOLE, 7)),
+            Ok(Some(Packet::Sync {
+                cid: CONSOLE,
+                sync_address: 7
+            }))
+        );
+        let packet = data.encode();
+        assert!(Packet::decode(&packet[..packet.len() - 1]).is_err());
+        assert_eq!(Packet::decode(b"not an E1.31 packet"), Ok(None));
+    }
+
+    #[test]
+    fn universes_map_to_pixels() {
+        let mut receiver = receiver();
+        let second = Data {
+            universe: 2,
+            ..Data::default()
+        };
+        assert_eq!(
+            receive(&mut receiver, &second.encode(), 0),
+            [(PIXELS_PER_UNIVERSE, vec![1, 2, 3])]
+        );
+        let outside = Data {
+            universe: 3,
+            ..Data::default()
+        };
+        assert_eq!(receive(&mut receiver, &outside.encode(), 0), []);
+    }
+
+    #[test]
+    fn higher_priority_takes_over() {
+        let mut receiver = receiver();
+        let console = |sequence, slots| Data {
+            sequence,
+            slots,
+            ..Data::default()
+        };
+        assert_eq!(
+            receive(&mut receiver, &console(1, &[1]).encode(), 0).len(),
+            1
+        );
+        // Same or lower priority is ignored while the console is sending.
+        let backup = |priority, slots| Data {
+            cid: BACKUP,
+            priority,
+            slots,
+            ..Data::default()
+        };
+        assert_eq!(
+            receive(&mut receiver, &backup(100, &[2]).encode(), 1).len(),
+            0
+        );
+        assert_eq!(
+            receive(&mut receiver, &backup(50, &[2]).encode(), 1).len(),
+            0
+        );
+        assert_eq!(
+            receive(&mut receiver, &backup(150, &[3]).encode(), 2),
+            [(0, vec![3])]
+        );
+        // Now the console is the lower priority one.
+        assert_eq!(
+            receive(&mut receiver, &console(2, &[1]).encode(), 3).len(),
+            0
+        );
+        // Until the backup goes quiet.
+        let later = 2 + NETWORK_DATA_LOSS_TIMEOUT_US;
+        assert_eq!(
+            receive(&mut receiver, &console(3, &[4]).encode(), later),
+            [(0, vec![4])]
+        );
+    }
+
+    #[test]
+    fn late_sequences() {
+        assert!(is_late(10, 10));
+        assert!(is_late(10, 0));
+        assert!(!is_late(10, 11));
+        assert!(is_late(5, 250));
+        assert!(!is_late(250, 5));
+        assert!(!is_late(30, 10));
+    }
+
+    #[test]
+    fn late_packets_are_dropped_across_wrap() {
+        let mut receiver = receiver();
+        let data = |sequence| {
+            Data {
+                sequence,
+                ..Data::default()
+            }
+            .encode()
+        };
+        assert_eq!(receive(&mut receiver, &data(250), 0).len(), 1);
+        assert_eq!(receive(&mut receiver, &data(250), 0).len(), 0);
+        assert_eq!(receive(&mut receiver, &data(240), 0).len(), 0);
+        // 255 wraps to 0.
+        assert_eq!(receive(&mut receiver, &data(4), 0).len(), 1);
+        assert_eq!(receive(&mut receiver, &data(253), 0).len(), 0);
+        // Far enough behind to be a restarted source.
+        assert_eq!(receive(&mut receiver, &data(200), 0).len(), 1);
+    }
+
+    #[test]
+    fn terminated_stream_frees_the_universe() {
+        let mut receiver = receiver();
+        assert_eq!(
+            receive(&mut receiver, &Data::default().encode(), 0).len(),
+            1
+        );
+        let stop = Data {
+            sequence: 2,
+            options: OPTION_STREAM_TERMINATED,
+            ..Data::default()
+        };
+        assert_eq!(receive(&mut receiver, &stop.encode(), 1).len(), 0);
+        let backup = Data {
+            cid: BACKUP,
+            priority: 10,
+            ..Data::default()
+        };
+        assert_eq!(receive(&mut receiver, &backup.encode(), 2).len(), 1);
+    }
+
+    #[test]
+    fn sync_releases_held_data() {
+        let mut receiver = receiver();
+        let mut joined = vec![];
+        let data = |universe, sequence| {
+            Data {
+                universe,
+                sequence,
+                sync_address: 100,
+                ..Data::default()
+            }
+            .encode()
+        };
+        // Shown as it comes until the first sync.
+        receiver.on_packet(&data(1, 1), 0, |_, _| {}, |group| joined.push(group));
+        assert_eq!(joined, [universe_group(100)]);
+        assert!(receiver.groups().any(|group| group == universe_group(100)));
+        assert_eq!(receive(&mut receiver, &sync(CONSOLE, 100), 1), []);
+
+        assert_eq!(receive(&mut receiver, &data(1, 2), 2), []);
+        assert_eq!(receive(&mut receiver, &data(2, 2), 2), []);
+        assert_eq!(receive(&mut receiver, &sync(CONSOLE, 99), 3), []);
+        assert_eq!(
+            receive(&mut receiver, &sync(CONSOLE, 100), 3),
+            [(0, vec![1, 2, 3]), (PIXELS_PER_UNIVERSE, vec![1, 2, 3])]
+        );
+        // Once syncs stop, data is shown as it comes again.
+        let later = 3 + NETWORK_DATA_LOSS_TIMEOUT_US;
+        assert_eq!(receive(&mut receiver, &data(1, 3), later).len(), 1);
+    }
+
+    #[test]
+    fn sync_from_another_source_is_ignored() {
+        let mut receiver = receiver();
+        let data = |cid, sequence| {
+            Data {
+                cid,
+                sequence,
+                sync_address: 100,
+                ..Data::default()
+            }
+            .encode()
+        };
+        assert_eq!(receive(&mut receiver, &data(CONSOLE, 1), 0).len(), 1);
+        // Another source syncing the address doesn't hold the console's data.
+        assert_eq!(receive(&mut receiver, &sync(BACKUP, 100), 1), []);
+        assert_eq!(receive(&mut receiver, &data(CONSOLE, 2), 2).len(), 1);
+
+        assert_eq!(receive(&mut receiver, &sync(CONSOLE, 100), 3), []);
+        assert_eq!(receive(&mut receiver, &data(CONSOLE, 3), 4), []);
+        // Nor release it.
+        assert_eq!(receive(&mut receiver, &sync(BACKUP, 100), 5), []);
+        assert_eq!(
+            receive(&mut receiver, &sync(CONSOLE, 100), 6),
+            [(0, vec![1, 2, 3])]
+        );
+    }
+}
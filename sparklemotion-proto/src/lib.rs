@@ -0,0 +1,2078 @@
+//! The SparkleMotion wire protocol spoken between Pinky, the mapper and the
+//! brains. Shared by all of the brain firmwares.
+//!
+//! `no_std`; the `alloc` feature adds encoders that return a `Vec`, message
+//! reassembly and the E1.31 and Art-Net receivers.
+
+#![no_std]
+
+#[cfg(feature = "alloc")]
+extern crate alloc;
+#[cfg(feature = "std")]
+extern crate std;
+
+#[cfg(feature = "alloc")]
+pub mod artnet;
+pub mod clock;
+#[cfg(feature = "alloc")]
+pub mod e131;
+#[cfg(feature = "alloc")]
+pub mod reassembly;
+pub mod sequence;
+
+/*
+    enum class Encoding : uint8_t {
+        DIRECT_ARGB = 0,
+        DIRECT_RGB,
+        INDEXED_2,
+        INDEXED_4,
+        INDEXED_16
+    };
+*/
+
+use core::fmt;
+
+#[cfg(feature = "alloc")]
+use alloc::{vec, vec::Vec};
+use embedded_io::{ErrorType, Write};
+
+#[repr(u8)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum MessageType {
+    BrainHello = 0u8,
+    BrainPanelShade = 1u8,
+    MapperHello,
+    BrainIdRequest,
+    BrainMapping,
+    Ping,
+    UseFirmware,
+    /// Brain -> Pinky: a delta shade arrived for a frame the brain doesn't
+    /// have, send the whole frame.
+    KeyframeRequest,
+    /// Brain -> Pinky, every few seconds: how the brain is doing.
+    Telemetry,
+    /// Pinky -> Brain: change LED and liveness settings at runtime.
+    Configure,
+    /// Pinky -> Brain: flash so someone on site can find the panel.
+    Identify,
+    /// Pinky -> Brain: restart the brain.
+    Reboot,
+}
+
+impl TryFrom<u8> for MessageType {
+    type Error = ProtoError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        Ok(match value {
+            0 => MessageType::BrainHello,
+            1 => MessageType::BrainPanelShade,
+            2 => MessageType::MapperHello,
+            3 => MessageType::BrainIdRequest,
+            4 => MessageType::BrainMapping,
+            5 => MessageType::Ping,
+            6 => MessageType::UseFirmware,
+            7 => MessageType::KeyframeRequest,
+            8 => MessageType::Telemetry,
+            9 => MessageType::Configure,
+            10 => MessageType::Identify,
+            11 => MessageType::Reboot,
+            _ => return Err(ProtoError::UnknownMessageType(value)),
+        })
+    }
+}
+
+/// Reasons an inbound packet could not be decoded. Anything off the network
+/// may be truncated or garbage, so decoding never panics and returns one of
+/// these instead.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProtoError {
+    /// The packet ended before the field being read.
+    Truncated,
+    /// A length prefix was negative.
+    InvalidLength(i32),
+    /// Header fields describe a frame that does not fit inside its message.
+    InvalidHeader,
+    /// Message type byte we don't know.
+    UnknownMessageType(u8),
+    UnknownShaderType(u8),
+    UnknownEncoding(u8),
+    UnknownChannel(u8),
+    UnknownColorOrder(u8),
+    /// Shader descriptor with a known type but the wrong parameters for it.
+    UnsupportedShader,
+    /// Field is larger than the fixed-capacity buffer it decodes into.
+    TooLong,
+    InvalidUtf8,
+}
+
+impl fmt::Display for ProtoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProtoError::Truncated => write!(f, "message truncated"),
+            ProtoError::InvalidLength(len) => write!(f, "invalid length {len}"),
+            ProtoError::InvalidHeader => write!(f, "invalid header"),
+            ProtoError::UnknownMessageType(t) => write!(f, "unknown message type {t}"),
+            ProtoError::UnknownShaderType(t) => write!(f, "unknown shader type {t}"),
+            ProtoError::UnknownEncoding(e) => write!(f, "unknown encoding {e}"),
+            ProtoError::UnknownChannel(c) => write!(f, "unknown channel {c}"),
+            ProtoError::UnknownColorOrder(o) => write!(f, "unknown color order {o}"),
+            ProtoError::UnsupportedShader => write!(f, "unsupported shader"),
+            ProtoError::TooLong => write!(f, "field too long"),
+            ProtoError::InvalidUtf8 => write!(f, "invalid utf-8"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for ProtoError {}
+
+#[derive(Debug, Clone)]
+pub struct Header {
+    pub id: i16,
+    pub frame_size: i16,
+    pub msg_size: i32,
+    pub frame_offset: i32,
+}
+
+/// Version of the protocol this crate speaks, advertised in BrainHello
+/// [`Capabilities`]. Brains that send no capabilities are version 0.
+pub const PROTOCOL_VERSION: u8 = 1;
+
+pub const FRAGMENT_MAX: usize = 1500;
+pub const PONG_DATA_MAX: usize = 16;
+pub const PANEL_NAME_MAX: usize = 64;
+pub const REBOOT_REASON_MAX: usize = 64;
+pub const HEADER_SIZE: usize = 12;
+/// Most message bytes one frame can carry, leaving room for its header.
+pub const FRAME_PAYLOAD_MAX: usize = FRAGMENT_MAX - HEADER_SIZE;
+
+impl Header {
+    pub fn to_bytes(&self) -> [u8; HEADER_SIZE] {
+        let mut buf = [0u8; HEADER_SIZE];
+        let mut w = buf.as_mut_slice();
+        w.write_all(&self.id.to_be_bytes()).unwrap();
+        w.write_all(&self.frame_size.to_be_bytes()).unwrap();
+        w.write_all(&self.msg_size.to_be_bytes()).unwrap();
+        w.write_all(&self.frame_offset.to_be_bytes()).unwrap();
+        buf
+    }
+    /// Splits a received datagram into its header and frame payload. The
+    /// payload is trimmed to `frame_size`.
+    pub fn decode(packet: &[u8]) -> Result<(Self, &[u8]), ProtoError> {
+        let mut r = Reader::new(packet);
+        let header = Self {
+            id: r.read_i16()?,
+            frame_size: r.read_i16()?,
+            msg_size: r.read_i32()?,
+            frame_offset: r.read_i32()?,
+        };
+        if header.frame_size < 0
+            || header.frame_offset < 0
+            || header.frame_offset as i64 + header.frame_size as i64 > header.msg_size as i64
+        {
+            return Err(ProtoError::InvalidHeader);
+        }
+        let payload = r.read_exact(header.frame_size as usize)?;
+        Ok((header, payload))
+    }
+}
+
+/// Bounds-checked cursor over a received message.
+pub struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        Self { buf }
+    }
+
+    /// Bytes not yet consumed.
+    pub fn remaining(&self) -> &'a [u8] {
+        self.buf
+    }
+
+    pub fn read_exact(&mut self, len: usize) -> Result<&'a [u8], ProtoError> {
+        if len > self.buf.len() {
+            return Err(ProtoError::Truncated);
+        }
+        let (out, rest) = self.buf.split_at(len);
+        self.buf = rest;
+        Ok(out)
+    }
+
+    pub fn read_array<const N: usize>(&mut self) -> Result<[u8; N], ProtoError> {
+        let mut out = [0u8; N];
+        out.copy_from_slice(self.read_exact(N)?);
+        Ok(out)
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8, ProtoError> {
+        Ok(self.read_array::<1>()?[0])
+    }
+
+    pub fn read_bool(&mut self) -> Result<bool, ProtoError> {
+        Ok(self.read_u8()? != 0)
+    }
+
+    pub fn read_u16(&mut self) -> Result<u16, ProtoError> {
+        Ok(u16::from_be_bytes(self.read_array()?))
+    }
+
+    pub fn read_i16(&mut self) -> Result<i16, ProtoError> {
+        Ok(i16::from_be_bytes(self.read_array()?))
+    }
+
+    pub fn read_i32(&mut self) -> Result<i32, ProtoError> {
+        Ok(i32::from_be_bytes(self.read_array()?))
+    }
+
+    pub fn read_u32(&mut self) -> Result<u32, ProtoError> {
+        Ok(u32::from_be_bytes(self.read_array()?))
+    }
+
+    pub fn read_u64(&mut self) -> Result<u64, ProtoError> {
+        Ok(u64::from_be_bytes(self.read_array()?))
+    }
+
+    /// Reads an int-prefixed byte array, as written by [`write_bytes`].
+    pub fn read_bytes(&mut self) -> Result<&'a [u8], ProtoError> {
+        let len = self.read_i32()?;
+        if len < 0 {
+            return Err(ProtoError::InvalidLength(len));
+        }
+        self.read_exact(len as usize)
+    }
+
+    pub fn read_str(&mut self) -> Result<&'a str, ProtoError> {
+        core::str::from_utf8(self.read_bytes()?).map_err(|_| ProtoError::InvalidUtf8)
+    }
+
+    pub fn read_str_opt(&mut self) -> Result<Option<&'a str>, ProtoError> {
+        if self.read_bool()? {
+            Ok(Some(self.read_str()?))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+/// A decoded message. Only the first frame of a message carries the message
+/// type, so this is decoded from frames with `frame_offset == 0`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Message<'a> {
+    BrainHello(BrainHello<'a>),
+    BrainPanelShade(BrainPanelShade<'a>),
+    BrainIdRequest,
+    UseFirmware(UseFirmware<'a>),
+    Ping(Ping),
+    BrainMapping(BrainMapping<'a>),
+    MapperHello,
+    KeyframeRequest,
+    Telemetry(Telemetry),
+    Configure(Configure),
+    Identify(Identify),
+    Reboot(Reboot<'a>),
+}
+
+impl<'a> Message<'a> {
+    pub fn decode(buf: &'a [u8]) -> Result<Self, ProtoError> {
+        let mut r = Reader::new(buf);
+        let msg_type = r.read_u8()?;
+        Ok(match MessageType::try_from(msg_type)? {
+            MessageType::BrainPanelShade => {
+                Message::BrainPanelShade(BrainPanelShade::decode(&mut r)?)
+            }
+            MessageType::BrainIdRequest => Message::BrainIdRequest,
+            MessageType::UseFirmware => Message::UseFirmware(UseFirmware::decode(&mut r)?),
+            MessageType::Ping => Message::Ping(Ping::decode(&mut r)?),
+            MessageType::BrainMapping => Message::BrainMapping(BrainMapping::decode(&mut r)?),
+            MessageType::MapperHello => Message::MapperHello,
+            MessageType::KeyframeRequest => Message::KeyframeRequest,
+            MessageType::Telemetry => Message::Telemetry(Telemetry::decode(&mut r)?),
+            MessageType::Configure => Message::Configure(Configure::decode(&mut r)?),
+            MessageType::Identify => Message::Identify(Identify::decode(&mut r)?),
+            MessageType::Reboot => Message::Reboot(Reboot::decode(&mut r)?),
+            MessageType::BrainHello => Message::BrainHello(BrainHello::decode(&mut r)?),
+        })
+    }
+
+    pub fn message_type(&self) -> MessageType {
+        match self {
+            Message::BrainHello(_) => MessageType::BrainHello,
+            Message::BrainPanelShade(_) => MessageType::BrainPanelShade,
+            Message::BrainIdRequest => MessageType::BrainIdRequest,
+            Message::UseFirmware(_) => MessageType::UseFirmware,
+            Message::Ping(_) => MessageType::Ping,
+            Message::BrainMapping(_) => MessageType::BrainMapping,
+            Message::MapperHello => MessageType::MapperHello,
+            Message::KeyframeRequest => MessageType::KeyframeRequest,
+            Message::Telemetry(_) => MessageType::Telemetry,
+            Message::Configure(_) => MessageType::Configure,
+            Message::Identify(_) => MessageType::Identify,
+            Message::Reboot(_) => MessageType::Reboot,
+        }
+    }
+}
+
+/// Bits of the BrainPanelShade byte that was a plain hasPongData bool before
+/// presentation times, so old shades decode the same.
+const SHADE_HAS_PONG_DATA: u8 = 1 << 0;
+/// Only sent to brains with [`Features::SCHEDULED_FRAMES`]: older brains
+/// read any non-zero byte as hasPongData.
+const SHADE_HAS_PRESENT_AT: u8 = 1 << 1;
+
+/*
+ * Brain Panel Shade message format:
+ * 12byte header | 0x01 (message type) | 1byte flags (hasPongData, hasPresentAt) |
+ * optional bytearray pong data (int + bytes) | optional u64 presentAt |
+ * | bytearray shader descrption  (2-bytes: 0x01 (PIXEL type),  0x01 (encoding RGB),
+ *   optionally followed by the channel byte)
+ * | shader data
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct BrainPanelShade<'a> {
+    pub pong_data: Option<&'a [u8]>,
+    /// When to show the shade, in microseconds on pinky's clock.
+    pub present_at_us: Option<u64>,
+    pub shader_desc: &'a [u8],
+    pub shader_data: &'a [u8],
+}
+
+impl<'a> BrainPanelShade<'a> {
+    pub fn shader(&self) -> Result<Shader<'a>, ProtoError> {
+        Shader::decode(
+            ShaderDescriptor::decode(self.shader_desc)?,
+            self.shader_data,
+        )
+    }
+
+    /// The LED output this shade is for.
+    pub fn channel(&self) -> Result<Channel, ProtoError> {
+        ShaderDescriptor::decode_with_channel(self.shader_desc).map(|(_, channel)| channel)
+    }
+
+    fn decode(r: &mut Reader<'a>) -> Result<Self, ProtoError> {
+        let flags = r.read_u8()?;
+        let pong_data = if flags & SHADE_HAS_PONG_DATA != 0 {
+            Some(r.read_bytes()?)
+        } else {
+            None
+        };
+        let present_at_us = if flags & SHADE_HAS_PRESENT_AT != 0 {
+            Some(r.read_u64()?)
+        } else {
+            None
+        };
+        let shader_desc = r.read_bytes()?;
+        Ok(Self {
+            pong_data,
+            present_at_us,
+            shader_desc,
+            shader_data: r.remaining(),
+        })
+    }
+
+    pub fn encode(&self, w: &mut impl Write) {
+        let mut flags = 0;
+        if self.pong_data.is_some() {
+            flags |= SHADE_HAS_PONG_DATA;
+        }
+        if self.present_at_us.is_some() {
+            flags |= SHADE_HAS_PRESENT_AT;
+        }
+        w.write_all(&[MessageType::BrainPanelShade as u8, flags])
+            .unwrap();
+        if let Some(pong_data) = self.pong_data {
+            write_bytes(w, pong_data);
+        }
+        if let Some(present_at_us) = self.present_at_us {
+            w.write_all(&present_at_us.to_be_bytes()).unwrap();
+        }
+        write_bytes(w, self.shader_desc);
+        w.write_all(self.shader_data).unwrap();
+    }
+}
+
+/// Splits an outbound message into frames of at most [`FRAME_PAYLOAD_MAX`]
+/// bytes. Yields each frame's header along with the part of the payload it
+/// carries. An empty payload still produces one (empty) frame.
+pub struct Fragments<'a> {
+    id: i16,
+    payload: &'a [u8],
+    offset: usize,
+    done: bool,
+}
+
+impl<'a> Fragments<'a> {
+    pub fn new(id: i16, payload: &'a [u8]) -> Self {
+        Self {
+            id,
+            payload,
+            offset: 0,
+            done: false,
+        }
+    }
+}
+
+impl<'a> Iterator for Fragments<'a> {
+    type Item = (Header, &'a [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let frame_size = (self.payload.len() - self.offset).min(FRAME_PAYLOAD_MAX);
+        let chunk = &self.payload[self.offset..self.offset + frame_size];
+        let header = Header {
+            id: self.id,
+            frame_size: frame_size as i16,
+            msg_size: self.payload.len() as i32,
+            frame_offset: self.offset as i32,
+        };
+        self.offset += frame_size;
+        self.done = self.offset == self.payload.len();
+        Some((header, chunk))
+    }
+}
+
+/// Datagrams, header included, for sending `payload` as message `msg_id`.
+#[cfg(feature = "alloc")]
+pub fn prepend_header(msg_id: i16, payload: &[u8]) -> impl Iterator<Item = Vec<u8>> + '_ {
+    Fragments::new(msg_id, payload).map(|(header, chunk)| {
+        let mut out = Vec::with_capacity(HEADER_SIZE + chunk.len());
+        out.extend_from_slice(&header.to_bytes());
+        out.extend_from_slice(chunk);
+        out
+    })
+}
+
+/// Like [`prepend_header`], without allocating.
+pub fn prepend_header_heapless(
+    msg_id: i16,
+    payload: &[u8],
+) -> impl Iterator<Item = heapless::Vec<u8, FRAGMENT_MAX>> + '_ {
+    Fragments::new(msg_id, payload).map(|(header, chunk)| {
+        let mut out = heapless::Vec::new();
+        // Frames are sized so header and chunk always fit in FRAGMENT_MAX.
+        out.extend_from_slice(&header.to_bytes()).unwrap();
+        out.extend_from_slice(chunk).unwrap();
+        out
+    })
+}
+
+/*
+ * Brain Mapping message format:
+ * 0x04 (message type) | string brainId | nullable string panelName | int pixelCount
+ * | int coordinateCount | coordinateCount * (float x, float y, float z)
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct BrainMapping<'a> {
+    pub brain_id: &'a str,
+    pub panel_name: Option<&'a str>,
+    pub pixel_count: u32,
+    coordinates: &'a [u8],
+}
+
+impl<'a> BrainMapping<'a> {
+    const COORDINATE_SIZE: usize = 3 * size_of::<f32>();
+
+    fn decode(r: &mut Reader<'a>) -> Result<Self, ProtoError> {
+        let brain_id = r.read_str()?;
+        let panel_name = r.read_str_opt()?;
+        let pixel_count = r.read_i32()?;
+        if pixel_count < 0 {
+            return Err(ProtoError::InvalidLength(pixel_count));
+        }
+        let coordinate_count = r.read_i32()?;
+        if coordinate_count < 0 {
+            return Err(ProtoError::InvalidLength(coordinate_count));
+        }
+        let coordinates_len = (coordinate_count as usize)
+            .checked_mul(Self::COORDINATE_SIZE)
+            .ok_or(ProtoError::InvalidLength(coordinate_count))?;
+        let coordinates = r.read_exact(coordinates_len)?;
+        Ok(Self {
+            brain_id,
+            panel_name,
+            pixel_count: pixel_count as u32,
+            coordinates,
+        })
+    }
+
+    /// Model-space `[x, y, z]` location of each pixel.
+    pub fn coordinates(&self) -> impl Iterator<Item = [f32; 3]> + 'a {
+        self.coordinates
+            .chunks_exact(Self::COORDINATE_SIZE)
+            .map(|c| {
+                let axis = |i: usize| f32::from_be_bytes([c[i], c[i + 1], c[i + 2], c[i + 3]]);
+                [axis(0), axis(4), axis(8)]
+            })
+    }
+}
+
+/*
+ * Brain Hello message format:
+ * 0x00 (message type) | string brainId | nullable string panelName
+ * | nullable string firmwareVersion | nullable string idfVersion
+ * | optional bytearray capabilities
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct BrainHello<'a> {
+    pub brain_id: &'a str,
+    pub panel_name: Option<&'a str>,
+    pub firmware_version: Option<&'a str>,
+    pub idf_version: Option<&'a str>,
+    pub capabilities: Option<Capabilities>,
+}
+
+impl<'a> BrainHello<'a> {
+    pub fn decode(r: &mut Reader<'a>) -> Result<Self, ProtoError> {
+        Ok(Self {
+            brain_id: r.read_str()?,
+            panel_name: r.read_str_opt()?,
+            firmware_version: r.read_str_opt()?,
+            idf_version: r.read_str_opt()?,
+            capabilities: if r.remaining().is_empty() {
+                None
+            } else {
+                Some(Capabilities::decode(r.read_bytes()?)?)
+            },
+        })
+    }
+
+    pub fn encode(&self, w: &mut impl Write) {
+        w.write_all(&[MessageType::BrainHello as u8]).unwrap();
+        write_str(w, self.brain_id);
+        write_str_opt(w, self.panel_name);
+        write_str_opt(w, self.firmware_version);
+        write_str_opt(w, self.idf_version);
+        if let Some(capabilities) = &self.capabilities {
+            write_bytes(w, &capabilities.to_bytes());
+        }
+    }
+
+    #[cfg(feature = "alloc")]
+    pub fn to_vec(&self) -> Vec<u8> {
+        let mut w = vec![];
+        self.encode(&mut w);
+        w
+    }
+
+    pub fn to_heapless(&self) -> heapless::Vec<u8, FRAGMENT_MAX> {
+        let mut w = VecWriter::new();
+        self.encode(&mut w);
+        w.buffer
+    }
+}
+
+/*
+ * Capabilities format, carried as a bytearray at the end of BrainHello so
+ * Pinky versions that don't know about it stop reading before it:
+ * byte protocolVersion | int encodings bitmask | int maxLeds | byte channels
+ * | int features bitmask
+ * Fields added later go at the end, and readers ignore bytes they don't know.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capabilities {
+    pub protocol_version: u8,
+    /// Bit `n` is set if the brain can render [`Encoding`] `n`.
+    pub encodings: u32,
+    pub max_leds: u32,
+    /// Number of LED data outputs.
+    pub channels: u8,
+    pub features: Features,
+}
+
+impl Capabilities {
+    const SIZE: usize = 14;
+
+    pub fn decode(block: &[u8]) -> Result<Self, ProtoError> {
+        let mut r = Reader::new(block);
+        Ok(Self {
+            protocol_version: r.read_u8()?,
+            encodings: r.read_u32()?,
+            max_leds: r.read_u32()?,
+            channels: r.read_u8()?,
+            features: Features(r.read_u32()?),
+        })
+    }
+
+    pub fn to_bytes(&self) -> heapless::Vec<u8, { Self::SIZE }> {
+        let mut w = VecWriter::new();
+        w.write_all(&[self.protocol_version]).unwrap();
+        w.write_all(&self.encodings.to_be_bytes()).unwrap();
+        w.write_all(&self.max_leds.to_be_bytes()).unwrap();
+        w.write_all(&[self.channels]).unwrap();
+        w.write_all(&self.features.0.to_be_bytes()).unwrap();
+        w.buffer
+    }
+
+    pub fn supports(&self, encoding: Encoding) -> bool {
+        self.encodings & encoding.bit() != 0
+    }
+}
+
+/// Optional features a brain advertises in its [`Capabilities`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Features(pub u32);
+
+impl Features {
+    /// Updates its firmware on UseFirmware.
+    pub const OTA: Features = Features(1 << 0);
+    /// Accepts BrainMapping and MapperHello.
+    pub const MAPPING: Features = Features(1 << 1);
+    /// Adds [`PongTimestamps`] to pongs.
+    pub const PONG_TIMESTAMPS: Features = Features(1 << 2);
+    /// Holds shades with a [`BrainPanelShade::present_at_us`] until then.
+    pub const SCHEDULED_FRAMES: Features = Features(1 << 3);
+    /// Accepts [`Configure`].
+    pub const CONFIGURE: Features = Features(1 << 4);
+    /// Accepts [`Identify`].
+    pub const IDENTIFY: Features = Features(1 << 5);
+    /// Accepts [`Reboot`], reporting its reason in [`Telemetry`].
+    pub const REBOOT: Features = Features(1 << 6);
+
+    pub fn contains(self, other: Features) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl core::ops::BitOr for Features {
+    type Output = Features;
+
+    fn bitor(self, rhs: Features) -> Features {
+        Features(self.0 | rhs.0)
+    }
+}
+
+/*
+ * Ping message format:
+ * 0x05 (message type) | bool isPong | bytearray data
+ * | optional, pongs only: long receivedUs | long reassembledUs | long displayedUs
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct Ping {
+    pub data: heapless::Vec<u8, PONG_DATA_MAX>,
+    pub is_pong: bool,
+    pub timestamps: Option<PongTimestamps>,
+}
+
+/// Brain-local times, in microseconds since the brain booted, added to the
+/// pong for a shade's pong data. Lets pinky split the round trip into
+/// network, decode and display time. Pinky's pongs to a brain's clock ping
+/// carry pinky's clock instead, see [`clock`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PongTimestamps {
+    /// First frame of the shade arrived.
+    pub received_us: u64,
+    /// The whole shade had arrived.
+    pub reassembled_us: u64,
+    /// The rendered frame was handed to the LED driver.
+    pub displayed_us: u64,
+}
+
+impl Ping {
+    pub fn decode(r: &mut Reader) -> Result<Self, ProtoError> {
+        let is_pong = r.read_bool()?;
+        let data = heapless::Vec::from_slice(r.read_bytes()?).map_err(|_| ProtoError::TooLong)?;
+        // Older brains don't send timestamps.
+        let timestamps = if r.remaining().is_empty() {
+            None
+        } else {
+            Some(PongTimestamps {
+                received_us: r.read_u64()?,
+                reassembled_us: r.read_u64()?,
+                displayed_us: r.read_u64()?,
+            })
+        };
+        Ok(Self {
+            data,
+            is_pong,
+            timestamps,
+        })
+    }
+
+    pub fn encode(&self, w: &mut impl Write) {
+        w.write_all(&[MessageType::Ping as u8]).unwrap();
+        write_bool(w, self.is_pong);
+        write_bytes(w, &self.data);
+        if let Some(t) = &self.timestamps {
+            for us in [t.received_us, t.reassembled_us, t.displayed_us] {
+                w.write_all(&us.to_be_bytes()).unwrap();
+            }
+        }
+    }
+
+    #[cfg(feature = "alloc")]
+    pub fn to_vec(&self) -> Vec<u8> {
+        let mut w = vec![];
+        self.encode(&mut w);
+        w
+    }
+
+    pub fn to_heapless(&self) -> heapless::Vec<u8, 64> {
+        let mut w = VecWriter::new();
+        self.encode(&mut w);
+        w.buffer
+    }
+}
+
+/// How a brain is connected.
+#[repr(u8)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum LinkType {
+    /// A link type added after this decoder.
+    Unknown,
+    Ethernet,
+    Wifi,
+}
+
+impl From<u8> for LinkType {
+    fn from(value: u8) -> Self {
+        match value {
+            1 => LinkType::Ethernet,
+            2 => LinkType::Wifi,
+            _ => LinkType::Unknown,
+        }
+    }
+}
+
+/*
+ * Telemetry message format:
+ * 0x08 (message type) | long uptimeMs | int framesReceived | int framesRendered
+ * | int framesDropped | int fragmentResets | int lateFrames | int missedDeltas
+ * | int freeHeap | int minFreeHeap | byte resetReason | byte linkType | 4 bytes ipv4
+ * | NullableString rebootReason
+ *
+ * Fields may be added at the end; decoders ignore what follows the ones they
+ * know.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct Telemetry {
+    pub uptime_ms: u64,
+    /// Network frames received.
+    pub frames_received: u32,
+    /// New frames handed to the LEDs.
+    pub frames_rendered: u32,
+    /// Network frames thrown away: malformed, late or duplicate.
+    pub frames_dropped: u32,
+    /// Messages given up on because a fragment never arrived.
+    pub fragment_resets: u32,
+    /// Scheduled frames shown after their presentation time.
+    pub late_frames: u32,
+    /// Delta shades dropped because their base frame was missing.
+    pub missed_deltas: u32,
+    pub free_heap: u32,
+    /// Least free heap since boot.
+    pub min_free_heap: u32,
+    /// ESP-IDF `esp_reset_reason_t` of the last reset.
+    pub reset_reason: u8,
+    pub link: LinkType,
+    pub ip: [u8; 4],
+    /// Reason given by the [`Reboot`] that caused the last reset, if one did.
+    pub reboot_reason: Option<heapless::String<REBOOT_REASON_MAX>>,
+}
+
+impl Telemetry {
+    pub const MAX_SIZE: usize = 1 + 8 + 8 * 4 + 1 + 1 + 4 + 1 + 4 + REBOOT_REASON_MAX;
+
+    pub fn decode(r: &mut Reader) -> Result<Self, ProtoError> {
+        Ok(Self {
+            uptime_ms: r.read_u64()?,
+            frames_received: r.read_u32()?,
+            frames_rendered: r.read_u32()?,
+            frames_dropped: r.read_u32()?,
+            fragment_resets: r.read_u32()?,
+            late_frames: r.read_u32()?,
+            missed_deltas: r.read_u32()?,
+            free_heap: r.read_u32()?,
+            min_free_heap: r.read_u32()?,
+            reset_reason: r.read_u8()?,
+            link: LinkType::from(r.read_u8()?),
+            ip: r.read_array()?,
+            // Older brains don't send a reboot reason.
+            reboot_reason: if r.remaining().is_empty() {
+                None
+            } else {
+                r.read_str_opt()?
+                    .map(|reason| reason.try_into().map_err(|_| ProtoError::TooLong))
+                    .transpose()?
+            },
+        })
+    }
+
+    pub fn encode(&self, w: &mut impl Write) {
+        w.write_all(&[MessageType::Telemetry as u8]).unwrap();
+        w.write_all(&self.uptime_ms.to_be_bytes()).unwrap();
+        for count in [
+            self.frames_received,
+            self.frames_rendered,
+            self.frames_dropped,
+            self.fragment_resets,
+            self.late_frames,
+            self.missed_deltas,
+            self.free_heap,
+            self.min_free_heap,
+        ] {
+            w.write_all(&count.to_be_bytes()).unwrap();
+        }
+        w.write_all(&[self.reset_reason, self.link as u8]).unwrap();
+        w.write_all(&self.ip).unwrap();
+        write_str_opt(w, self.reboot_reason.as_deref());
+    }
+
+    pub fn to_heapless(&self) -> heapless::Vec<u8, { Telemetry::MAX_SIZE }> {
+        let mut w = VecWriter::new();
+        self.encode(&mut w);
+        w.buffer
+    }
+}
+
+/// Order the LED strip expects a pixel's colors in.
+#[repr(u8)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ColorOrder {
+    Rgb,
+    Rbg,
+    Grb,
+    Gbr,
+    Brg,
+    Bgr,
+}
+
+impl TryFrom<u8> for ColorOrder {
+    type Error = ProtoError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        Ok(match value {
+            0 => ColorOrder::Rgb,
+            1 => ColorOrder::Rbg,
+            2 => ColorOrder::Grb,
+            3 => ColorOrder::Gbr,
+            4 => ColorOrder::Brg,
+            5 => ColorOrder::Bgr,
+            _ => return Err(ProtoError::UnknownColorOrder(value)),
+        })
+    }
+}
+
+impl ColorOrder {
+    /// `[r, g, b]` rearranged into this order.
+    pub fn arrange(self, [r, g, b]: [u8; 3]) -> [u8; 3] {
+        match self {
+            ColorOrder::Rgb => [r, g, b],
+            ColorOrder::Rbg => [r, b, g],
+            ColorOrder::Grb => [g, r, b],
+            ColorOrder::Gbr => [g, b, r],
+            ColorOrder::Brg => [b, r, g],
+            ColorOrder::Bgr => [b, g, r],
+        }
+    }
+}
+
+// Bits of the Configure fields byte, one per optional field.
+const CONFIGURE_LED_COUNTS: u8 = 1 << 0;
+const CONFIGURE_MAX_FPS: u8 = 1 << 1;
+const CONFIGURE_COLOR_ORDER: u8 = 1 << 2;
+const CONFIGURE_GAMMA: u8 = 1 << 3;
+const CONFIGURE_BRIGHTNESS: u8 = 1 << 4;
+const CONFIGURE_LIVENESS_TIMEOUT: u8 = 1 << 5;
+const CONFIGURE_MULTICAST_GROUP: u8 = 1 << 6;
+const CONFIGURE_DMX_UNIVERSES: u8 = 1 << 7;
+
+/*
+ * Configure message format:
+ * 0x09 (message type) | byte fields (bitmask of the fields that follow)
+ * | if fields & 0x01: short ch1Leds | short ch2Leds
+ * | if fields & 0x02: byte maxFps
+ * | if fields & 0x04: byte colorOrder
+ * | if fields & 0x08: bool gamma
+ * | if fields & 0x10: byte brightness
+ * | if fields & 0x20: int livenessTimeoutMs
+ * | if fields & 0x40: 4 bytes multicastGroup (ipv4, 0.0.0.0 for none)
+ * | if fields & 0x80: short firstDmxUniverse | short dmxUniverseCount
+ *
+ * Fields that are left out keep their current value. The brain checks the
+ * values against its own limits.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Configure {
+    /// LEDs on each output, CH1 first.
+    pub led_counts: Option<[u16; 2]>,
+    /// Most times a second the LEDs are refreshed.
+    pub max_fps: Option<u8>,
+    pub color_order: Option<ColorOrder>,
+    /// Gamma correction and dithering, off sends pixels to the LEDs as is.
+    pub gamma: Option<bool>,
+    /// Scales every pixel by `brightness / 255`.
+    pub brightness: Option<u8>,
+    /// How long without hearing from Pinky before the brain says hello again.
+    pub liveness_timeout_ms: Option<u32>,
+    /// IPv4 multicast group to join for discovery and control messages, as
+    /// well as broadcast. `[0, 0, 0, 0]` leaves it.
+    pub multicast_group: Option<[u8; 4]>,
+    /// DMX universes the brain takes pixels from over E1.31.
+    pub dmx_universes: Option<DmxUniverses>,
+}
+
+/// A run of DMX universes, each carrying the next 170 RGB pixels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DmxUniverses {
+    pub first: u16,
+    /// 0 turns DMX input off.
+    pub count: u16,
+}
+
+impl Configure {
+    pub const MAX_SIZE: usize = 1 + 1 + 4 + 1 + 1 + 1 + 1 + 4 + 4 + 4;
+
+    pub fn decode(r: &mut Reader) -> Result<Self, ProtoError> {
+        let fields = r.read_u8()?;
+        let has = |bit| fields & bit != 0;
+        Ok(Self {
+            led_counts: has(CONFIGURE_LED_COUNTS)
+                .then(|| Ok::<_, ProtoError>([r.read_u16()?, r.read_u16()?]))
+                .transpose()?,
+            max_fps: has(CONFIGURE_MAX_FPS).then(|| r.read_u8()).transpose()?,
+            color_order: has(CONFIGURE_COLOR_ORDER)
+                .then(|| ColorOrder::try_from(r.read_u8()?))
+                .transpose()?,
+            gamma: has(CONFIGURE_GAMMA).then(|| r.read_bool()).transpose()?,
+            brightness: has(CONFIGURE_BRIGHTNESS).then(|| r.read_u8()).transpose()?,
+            liveness_timeout_ms: has(CONFIGURE_LIVENESS_TIMEOUT)
+                .then(|| r.read_u32())
+                .transpose()?,
+            multicast_group: has(CONFIGURE_MULTICAST_GROUP)
+                .then(|| r.read_array())
+                .transpose()?,
+            dmx_universes: has(CONFIGURE_DMX_UNIVERSES)
+                .then(|| {
+                    Ok::<_, ProtoError>(DmxUniverses {
+                        first: r.read_u16()?,
+                        count: r.read_u16()?,
+                    })
+                })
+                .transpose()?,
+        })
+    }
+
+    pub fn encode(&self, w: &mut impl Write) {
+        let mut fields = 0;
+        for (bit, set) in [
+            (CONFIGURE_LED_COUNTS, self.led_counts.is_some()),
+            (CONFIGURE_MAX_FPS, self.max_fps.is_some()),
+            (CONFIGURE_COLOR_ORDER, self.color_order.is_some()),
+            (CONFIGURE_GAMMA, self.gamma.is_some()),
+            (CONFIGURE_BRIGHTNESS, self.brightness.is_some()),
+            (
+                CONFIGURE_LIVENESS_TIMEOUT,
+                self.liveness_timeout_ms.is_some(),
+            ),
+            (CONFIGURE_MULTICAST_GROUP, self.multicast_group.is_some()),
+            (CONFIGURE_DMX_UNIVERSES, self.dmx_universes.is_some()),
+        ] {
+            if set {
+                fields |= bit;
+            }
+        }
+        w.write_all(&[MessageType::Configure as u8, fields])
+            .unwrap();
+        if let Some(led_counts) = self.led_counts {
+            for count in led_counts {
+                w.write_all(&count.to_be_bytes()).unwrap();
+            }
+        }
+        if let Some(max_fps) = self.max_fps {
+            w.write_all(&[max_fps]).unwrap();
+        }
+        if let Some(color_order) = self.color_order {
+            w.write_all(&[color_order as u8]).unwrap();
+        }
+        if let Some(gamma) = self.gamma {
+            write_bool(w, gamma);
+        }
+        if let Some(brightness) = self.brightness {
+            w.write_all(&[brightness]).unwrap();
+        }
+        if let Some(timeout) = self.liveness_timeout_ms {
+            w.write_all(&timeout.to_be_bytes()).unwrap();
+        }
+        if let Some(group) = self.multicast_group {
+            w.write_all(&group).unwrap();
+        }
+        if let Some(universes) = self.dmx_universes {
+            w.write_all(&universes.first.to_be_bytes()).unwrap();
+            w.write_all(&universes.count.to_be_bytes()).unwrap();
+        }
+    }
+
+    pub fn to_heapless(&self) -> heapless::Vec<u8, { Configure::MAX_SIZE }> {
+        let mut w = VecWriter::new();
+        self.encode(&mut w);
+        w.buffer
+    }
+}
+
+/*
+ * Identify message format:
+ * 0x0a (message type) | int durationMs
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Identify {
+    /// How long to flash for, 0 stops flashing.
+    pub duration_ms: u32,
+}
+
+impl Identify {
+    pub fn decode(r: &mut Reader) -> Result<Self, ProtoError> {
+        Ok(Self {
+            duration_ms: r.read_u32()?,
+        })
+    }
+
+    pub fn encode(&self, w: &mut impl Write) {
+        w.write_all(&[MessageType::Identify as u8]).unwrap();
+        w.write_all(&self.duration_ms.to_be_bytes()).unwrap();
+    }
+}
+
+/*
+ * Reboot message format:
+ * 0x0b (message type) | int delayMs | String reason
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct Reboot<'a> {
+    /// How long to wait before restarting.
+    pub delay_ms: u32,
+    /// Why, reported in the brain's [`Telemetry`] once it is back. At most
+    /// [`REBOOT_REASON_MAX`] bytes.
+    pub reason: &'a str,
+}
+
+impl<'a> Reboot<'a> {
+    pub fn decode(r: &mut Reader<'a>) -> Result<Self, ProtoError> {
+        Ok(Self {
+            delay_ms: r.read_u32()?,
+            reason: r.read_str()?,
+        })
+    }
+
+    pub fn encode(&self, w: &mut impl Write) {
+        w.write_all(&[MessageType::Reboot as u8]).unwrap();
+        w.write_all(&self.delay_ms.to_be_bytes()).unwrap();
+        write_str(w, self.reason);
+    }
+}
+
+#[derive(Default)]
+pub struct VecWriter<const N: usize> {
+    pub buffer: heapless::Vec<u8, N>,
+}
+
+impl<const N: usize> VecWriter<N> {
+    pub fn new() -> Self {
+        Self {
+            buffer: heapless::Vec::new(),
+        }
+    }
+}
+
+impl<const N: usize> ErrorType for VecWriter<N> {
+    type Error = embedded_io::ErrorKind;
+}
+impl<const N: usize> Write for VecWriter<N> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        let available_space = self.buffer.capacity() - self.buffer.len();
+        let write_len = buf.len().min(available_space);
+        if write_len == 0 && !buf.is_empty() {
+            return Err(embedded_io::ErrorKind::OutOfMemory);
+        }
+        // Can't fail, write_len fits in the remaining capacity.
+        let _ = self.buffer.extend_from_slice(&buf[..write_len]);
+        Ok(write_len)
+    }
+
+    fn flush(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+#[repr(u8)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ShaderType {
+    Solid,
+    Pixel,
+}
+
+impl TryFrom<u8> for ShaderType {
+    type Error = ProtoError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        Ok(match value {
+            0 => ShaderType::Solid,
+            1 => ShaderType::Pixel,
+            _ => return Err(ProtoError::UnknownShaderType(value)),
+        })
+    }
+}
+
+#[repr(u8)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Encoding {
+    DirectArgb,
+    DirectRgb,
+    /// Palette of two colors, indicated by one bit per pixel
+    /// Palette is of the form [a, r, g, b] * palette size.
+    Indexed2,
+    /// Palette of four colors, two bits per pixel.
+    Indexed4,
+    /// Palette of sixteen colors, four bits per pixel.
+    Indexed16,
+    /// Runs of one RGB color, see [`rle_runs`].
+    RleRgb,
+    /// Changes to an earlier frame, see [`Delta`].
+    Delta,
+}
+
+impl TryFrom<u8> for Encoding {
+    type Error = ProtoError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        Ok(match value {
+            0 => Encoding::DirectArgb,
+            1 => Encoding::DirectRgb,
+            2 => Encoding::Indexed2,
+            3 => Encoding::Indexed4,
+            4 => Encoding::Indexed16,
+            5 => Encoding::RleRgb,
+            6 => Encoding::Delta,
+            _ => return Err(ProtoError::UnknownEncoding(value)),
+        })
+    }
+}
+
+impl Encoding {
+    /// This encoding's bit in [`Capabilities::encodings`].
+    pub fn bit(self) -> u32 {
+        1 << self as u8
+    }
+
+    /// [`Capabilities::encodings`] bitmask for a set of encodings.
+    pub fn mask(encodings: &[Encoding]) -> u32 {
+        encodings.iter().fold(0, |mask, e| mask | e.bit())
+    }
+
+    /// Number of ARGB palette entries preceding the pixel data.
+    pub fn palette_size(self) -> usize {
+        match self {
+            Encoding::DirectArgb | Encoding::DirectRgb | Encoding::RleRgb | Encoding::Delta => 0,
+            Encoding::Indexed2 => 2,
+            Encoding::Indexed4 => 4,
+            Encoding::Indexed16 => 16,
+        }
+    }
+
+    /// Bits per pixel before compression.
+    pub fn bits_per_pixel(self) -> usize {
+        match self {
+            Encoding::DirectArgb => 32,
+            Encoding::DirectRgb | Encoding::RleRgb | Encoding::Delta => 24,
+            Encoding::Indexed2 => 1,
+            Encoding::Indexed4 => 2,
+            Encoding::Indexed16 => 4,
+        }
+    }
+}
+
+/// LED data output a shade is drawn on. Brains with two outputs split the
+/// panel's pixels between them, CH1's first.
+#[repr(u8)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum Channel {
+    /// The whole panel, across every channel.
+    #[default]
+    All,
+    Ch1,
+    Ch2,
+}
+
+impl TryFrom<u8> for Channel {
+    type Error = ProtoError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        Ok(match value {
+            0 => Channel::All,
+            1 => Channel::Ch1,
+            2 => Channel::Ch2,
+            _ => return Err(ProtoError::UnknownChannel(value)),
+        })
+    }
+}
+
+/// The shader description bytearray at the start of a BrainPanelShade's
+/// shader section: the shader type, then for pixel shaders the encoding, then
+/// optionally the [`Channel`]. Without it the shade is for the whole panel.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ShaderDescriptor {
+    Solid,
+    Pixel(Encoding),
+}
+
+impl ShaderDescriptor {
+    pub fn decode(desc: &[u8]) -> Result<Self, ProtoError> {
+        Self::decode_with_channel(desc).map(|(desc, _)| desc)
+    }
+
+    pub fn decode_with_channel(desc: &[u8]) -> Result<(Self, Channel), ProtoError> {
+        let (&shader_type, params) = desc.split_first().ok_or(ProtoError::Truncated)?;
+        let (desc, rest) = match (ShaderType::try_from(shader_type)?, params) {
+            (ShaderType::Solid, rest) => (ShaderDescriptor::Solid, rest),
+            (ShaderType::Pixel, [encoding, rest @ ..]) => (
+                ShaderDescriptor::Pixel(Encoding::try_from(*encoding)?),
+                rest,
+            ),
+            (ShaderType::Pixel, []) => return Err(ProtoError::UnsupportedShader),
+        };
+        let channel = match rest {
+            [] => Channel::All,
+            &[channel] => Channel::try_from(channel)?,
+            _ => return Err(ProtoError::UnsupportedShader),
+        };
+        Ok((desc, channel))
+    }
+
+    pub fn to_bytes(&self) -> heapless::Vec<u8, 3> {
+        self.to_bytes_for(Channel::All)
+    }
+
+    /// Descriptor for a shade drawn on `channel` only. The channel byte is
+    /// left off for [`Channel::All`] so older brains still accept it.
+    pub fn to_bytes_for(&self, channel: Channel) -> heapless::Vec<u8, 3> {
+        let mut out = heapless::Vec::new();
+        match self {
+            ShaderDescriptor::Solid => {
+                out.push(ShaderType::Solid as u8).unwrap();
+            }
+            ShaderDescriptor::Pixel(encoding) => {
+                out.extend_from_slice(&[ShaderType::Pixel as u8, *encoding as u8])
+                    .unwrap();
+            }
+        }
+        if channel != Channel::All {
+            out.push(channel as u8).unwrap();
+        }
+        out
+    }
+
+    /// Writes the descriptor as the bytearray BrainPanelShade carries it in.
+    pub fn encode(&self, w: &mut impl Write) {
+        write_bytes(w, &self.to_bytes());
+    }
+}
+
+/// Shader data decoded according to its [`ShaderDescriptor`].
+pub enum Shader<'a> {
+    /// One color for the whole panel.
+    Solid { argb: [u8; 4] },
+    Pixel {
+        encoding: Encoding,
+        pixel_count: u16,
+        /// `encoding.palette_size()` ARGB entries.
+        palette: &'a [u8],
+        data: &'a [u8],
+    },
+}
+
+impl<'a> Shader<'a> {
+    pub fn decode(desc: ShaderDescriptor, data: &'a [u8]) -> Result<Self, ProtoError> {
+        let mut r = Reader::new(data);
+        Ok(match desc {
+            ShaderDescriptor::Solid => Shader::Solid {
+                argb: r.read_array()?,
+            },
+            ShaderDescriptor::Pixel(encoding) => Shader::Pixel {
+                encoding,
+                pixel_count: r.read_u16()?,
+                palette: r.read_exact(encoding.palette_size() * 4)?,
+                data: r.remaining(),
+            },
+        })
+    }
+}
+
+/// Runs in [`Encoding::RleRgb`] data: a u8 count, then the RGB color of that
+/// many pixels. A trailing partial run is ignored.
+pub fn rle_runs(data: &[u8]) -> impl Iterator<Item = (usize, [u8; 3])> + '_ {
+    data.chunks_exact(4)
+        .map(|run| (run[0] as usize, [run[1], run[2], run[3]]))
+}
+
+/// Writes RGB pixels as [`Encoding::RleRgb`] data.
+pub fn write_rle(w: &mut impl Write, rgb: &[u8]) {
+    let mut pixels = rgb.chunks_exact(3).peekable();
+    while let Some(color) = pixels.next() {
+        let mut count = 1u8;
+        while count < u8::MAX && pixels.next_if_eq(&color).is_some() {
+            count += 1;
+        }
+        w.write_all(&[count]).unwrap();
+        w.write_all(color).unwrap();
+    }
+}
+
+/// How a [`Delta`] changes its base frame.
+#[repr(u8)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum DeltaOp {
+    /// [`rle_runs`] of RGB values XORed into the base frame from its first
+    /// pixel, so unchanged stretches are runs of zeros.
+    Xor,
+    /// [`Patch`]es of new RGB values, pixels outside them are unchanged.
+    Patch,
+}
+
+/// [`Encoding::Delta`] pixel data: changes to the frame drawn by the shade
+/// with message id `base_id` from the same sender. A brain that didn't draw
+/// that frame last can't apply it and asks for a keyframe, any other encoding,
+/// with [`MessageType::KeyframeRequest`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct Delta<'a> {
+    pub base_id: i16,
+    pub op: DeltaOp,
+    pub data: &'a [u8],
+}
+
+impl<'a> Delta<'a> {
+    pub fn decode(data: &'a [u8]) -> Result<Self, ProtoError> {
+        let mut r = Reader::new(data);
+        let base_id = r.read_i16()?;
+        let op = match r.read_u8()? {
+            0 => DeltaOp::Xor,
+            1 => DeltaOp::Patch,
+            _ => return Err(ProtoError::UnsupportedShader),
+        };
+        Ok(Self {
+            base_id,
+            op,
+            data: r.remaining(),
+        })
+    }
+
+    pub fn encode(&self, w: &mut impl Write) {
+        w.write_all(&self.base_id.to_be_bytes()).unwrap();
+        w.write_all(&[self.op as u8]).unwrap();
+        w.write_all(self.data).unwrap();
+    }
+
+    /// Patches of a [`DeltaOp::Patch`] delta, ending after the first error.
+    pub fn patches(&self) -> impl Iterator<Item = Result<Patch<'a>, ProtoError>> {
+        let mut r = Reader::new(self.data);
+        core::iter::from_fn(move || {
+            if r.remaining().is_empty() {
+                return None;
+            }
+            let patch = Patch::decode(&mut r);
+            if patch.is_err() {
+                r = Reader::new(&[]);
+            }
+            Some(patch)
+        })
+    }
+}
+
+/// New RGB values for `rgb.len() / 3` pixels from pixel `start`. Encoded as
+/// u16 start, u16 pixel count, then the pixels.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Patch<'a> {
+    pub start: u16,
+    pub rgb: &'a [u8],
+}
+
+impl<'a> Patch<'a> {
+    fn decode(r: &mut Reader<'a>) -> Result<Self, ProtoError> {
+        let start = r.read_u16()?;
+        let count = r.read_u16()?;
+        Ok(Self {
+            start,
+            rgb: r.read_exact(count as usize * 3)?,
+        })
+    }
+
+    pub fn encode(&self, w: &mut impl Write) {
+        w.write_all(&self.start.to_be_bytes()).unwrap();
+        w.write_all(&((self.rgb.len() / 3) as u16).to_be_bytes())
+            .unwrap();
+        w.write_all(&self.rgb[..self.rgb.len() / 3 * 3]).unwrap();
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct UseFirmware<'a> {
+    pub url: &'a str,
+}
+
+impl<'a> UseFirmware<'a> {
+    pub fn decode(r: &mut Reader<'a>) -> Result<Self, ProtoError> {
+        Ok(Self { url: r.read_str()? })
+    }
+
+    pub fn encode(&self, w: &mut impl Write) {
+        w.write_all(&[MessageType::UseFirmware as u8]).unwrap();
+        write_str(w, self.url);
+    }
+
+    #[cfg(feature = "alloc")]
+    pub fn to_vec(&self) -> Vec<u8> {
+        let mut w = vec![];
+        self.encode(&mut w);
+        w
+    }
+}
+
+/*
+    enum class Type : uint8_t {
+        BRAIN_HELLO,       // Brain -> Pinky|Mapper
+        BRAIN_PANEL_SHADE, // Pinky -> Brain
+        MAPPER_HELLO,      // Mapper -> Pinky
+        BRAIN_ID_REQUEST,  // Mapper -> Brain
+        BRAIN_MAPPING,
+        PING,
+        USE_FIRMWARE,
+    };
+
+    static const size_t FRAGMENT_MAX = 1500;
+    static const size_t HEADER_SIZE = 12;
+
+    struct Header {
+        int16_t id;
+        int16_t frameSize;
+        int32_t msgSize;
+        int32_t frameOffset;
+    };
+
+    BrainHelloMsg(const char *brainId,
+            const char *panelName,
+            const char *firmwareVersion,
+            const char *idfVersion) {
+        // Need capacity for:
+        //      id byte
+        //      brainId string
+        //      panelName NullableString (adds 1 byte boolean)
+        //      firmwareVersion string
+        //      idfVersion string
+        if (prepCapacity(
+                1 +
+                capFor(brainId) +
+                capForNullable(panelName) +
+                capForNullable(firmwareVersion) +
+                capForNullable(idfVersion)
+                )) {
+
+            writeByte(static_cast<int>(Msg::Type::BRAIN_HELLO));
+            writeString(brainId);
+            writeNullableString(panelName);
+            writeNullableString(firmwareVersion);
+            writeNullableString(idfVersion);
+        }
+    }
+
+    void writeString(const char* sz) {
+        if (!sz) return;
+
+        size_t len = strlen(sz);
+        size_t xtra = capFor(sz);
+        if (prepCapacity(m_used + xtra)) {
+            writeInt(len);
+            for ( int i = 0; i < len; i++ ) {
+                m_buf[m_cursor++] = (uint8_t)sz[i];
+            }
+            if (m_cursor > m_used) m_used = m_cursor;
+        }
+    }
+*/
+
+/// BrainHello payload, without a header. Send with [`prepend_header_heapless`].
+pub fn create_hello_msg(
+    brain_id: &str,
+    panel_name: Option<&str>,
+    firmware_version: Option<&str>,
+) -> heapless::Vec<u8, FRAGMENT_MAX> {
+    let mut out = VecWriter::new();
+    write_hello_msg(&mut out, brain_id, panel_name, firmware_version);
+
+    out.buffer
+}
+
+pub fn write_hello_msg(
+    w: &mut impl Write,
+    brain_id: &str,
+    panel_name: Option<&str>,
+    version: Option<&str>,
+) {
+    /*
+            writeByte(BRAIN_HELLO);
+            writeString(brainId);
+            writeNullableString(panelName);
+            writeNullableString(firmwareVersion);
+            writeNullableString(idfVersion);
+    */
+    BrainHello {
+        brain_id,
+        panel_name,
+        firmware_version: version,
+        idf_version: None,
+        capabilities: None,
+    }
+    .encode(w);
+}
+
+pub fn write_bool(w: &mut impl Write, b: bool) {
+    w.write_all(&[if b { 1 } else { 0 }]).unwrap();
+}
+
+pub fn write_bytes(w: &mut impl Write, s: &[u8]) {
+    let len = s.len() as u32;
+    w.write_all(len.to_be_bytes().as_slice()).unwrap();
+    w.write_all(s).unwrap();
+}
+
+pub fn write_str(w: &mut impl Write, s: &str) {
+    let len = s.len() as u32;
+    w.write_all(len.to_be_bytes().as_slice()).unwrap();
+    w.write_all(s.as_bytes()).unwrap();
+}
+
+pub fn write_str_opt(w: &mut impl Write, s: Option<&str>) {
+    if let Some(s) = s {
+        w.write_all(&[1]).unwrap();
+        write_str(w, s);
+    } else {
+        w.write_all(&[0]).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+
+    fn shade_msg(pong: Option<&[u8]>, desc: ShaderDescriptor, data: &[u8]) -> VecWriter<256> {
+        let mut w = VecWriter::new();
+        w.write_all(&[MessageType::BrainPanelShade as u8]).unwrap();
+        write_bool(&mut w, pong.is_some());
+        if let Some(pong) = pong {
+            write_bytes(&mut w, pong);
+        }
+        desc.encode(&mut w);
+        w.write_all(data).unwrap();
+        w
+    }
+
+    #[test]
+    fn header_round_trip() {
+        let header = Header {
+            id: -2,
+            frame_size: 3,
+            msg_size: 10,
+            frame_offset: 7,
+        };
+        let mut packet = header.to_bytes().to_vec();
+        packet.extend_from_slice(&[1, 2, 3, 4]);
+        let (decoded, payload) = Header::decode(&packet).unwrap();
+        assert_eq!(decoded.id, -2);
+        assert_eq!(decoded.msg_size, 10);
+        assert_eq!(decoded.frame_offset, 7);
+        // Trailing bytes past frame_size are not part of the frame.
+        assert_eq!(payload, &[1, 2, 3]);
+    }
+
+    #[test]
+    fn header_rejects_bad_frames() {
+        let frame = |frame_size, msg_size, frame_offset| {
+            let mut packet = Header {
+                id: 0,
+                frame_size,
+                msg_size,
+                frame_offset,
+            }
+            .to_bytes()
+            .to_vec();
+            packet.resize(packet.len() + 16, 0);
+            Header::decode(&packet).map(|_| ())
+        };
+        assert_eq!(frame(8, 4, 0), Err(ProtoError::InvalidHeader));
+        assert_eq!(frame(4, 8, 6), Err(ProtoError::InvalidHeader));
+        assert_eq!(frame(-1, 8, 0), Err(ProtoError::InvalidHeader));
+        assert_eq!(frame(4, 8, -4), Err(ProtoError::InvalidHeader));
+        assert_eq!(frame(20, 20, 0), Err(ProtoError::Truncated));
+        assert_eq!(Header::decode(&[0; 11]).err(), Some(ProtoError::Truncated));
+    }
+
+    #[test]
+    fn fragments_cover_payload() {
+        let payload: [u8; 3000] = core::array::from_fn(|i| i as u8);
+        let mut reassembled = [0u8; 3000];
+        let mut frames = 0;
+        for packet in prepend_header_heapless(5, &payload) {
+            assert!(packet.len() <= FRAGMENT_MAX);
+            let (header, chunk) = Header::decode(&packet).unwrap();
+            assert_eq!(header.id, 5);
+            assert_eq!(header.msg_size, 3000);
+            let offset = header.frame_offset as usize;
+            reassembled[offset..offset + chunk.len()].copy_from_slice(chunk);
+            frames += 1;
+        }
+        assert_eq!(frames, 3);
+        assert_eq!(reassembled, payload);
+
+        let mut empty = Fragments::new(0, &[]);
+        let (header, chunk) = empty.next().unwrap();
+        assert_eq!((header.frame_size, header.msg_size), (0, 0));
+        assert!(chunk.is_empty());
+        assert!(empty.next().is_none());
+    }
+
+    #[test]
+    fn decode_shade() {
+        let data = [0, 3, 255, 0, 0, 0, 255, 9, 8, 7, 0b0100_0000];
+        let msg = shade_msg(
+            Some(&[0xaa, 0xbb]),
+            ShaderDescriptor::Pixel(Encoding::Indexed2),
+            &data,
+        );
+        let Ok(Message::BrainPanelShade(shade)) = Message::decode(&msg.buffer) else {
+            panic!("expected a shade");
+        };
+        assert_eq!(shade.pong_data, Some(&[0xaa, 0xbb][..]));
+        let Ok(Shader::Pixel {
+            encoding,
+            pixel_count,
+            palette,
+            data,
+        }) = shade.shader()
+        else {
+            panic!("expected a pixel shader");
+        };
+        assert_eq!(encoding, Encoding::Indexed2);
+        assert_eq!(pixel_count, 3);
+        assert_eq!(palette, &[255, 0, 0, 0, 255, 9, 8, 7]);
+        assert_eq!(data, &[0b0100_0000]);
+
+        let msg = shade_msg(None, ShaderDescriptor::Solid, &[255, 1, 2, 3]);
+        let Ok(Message::BrainPanelShade(shade)) = Message::decode(&msg.buffer) else {
+            panic!("expected a shade");
+        };
+        assert!(shade.pong_data.is_none());
+        assert!(matches!(
+            shade.shader(),
+            Ok(Shader::Solid {
+                argb: [255, 1, 2, 3]
+            })
+        ));
+    }
+
+    #[test]
+    fn decode_shader_descriptor() {
+        for desc in [
+            ShaderDescriptor::Solid,
+            ShaderDescriptor::Pixel(Encoding::DirectArgb),
+            ShaderDescriptor::Pixel(Encoding::Indexed16),
+        ] {
+            assert_eq!(ShaderDescriptor::decode(&desc.to_bytes()), Ok(desc));
+        }
+        assert_eq!(
+            ShaderDescriptor::decode(&[1, 99]),
+            Err(ProtoError::UnknownEncoding(99))
+        );
+        assert_eq!(
+            ShaderDescriptor::decode(&[7]),
+            Err(ProtoError::UnknownShaderType(7))
+        );
+        assert_eq!(
+            ShaderDescriptor::decode(&[0, 1, 2]),
+            Err(ProtoError::UnsupportedShader)
+        );
+        assert_eq!(
+            ShaderDescriptor::decode(&[1]),
+            Err(ProtoError::UnsupportedShader)
+        );
+        assert_eq!(ShaderDescriptor::decode(&[]), Err(ProtoError::Truncated));
+    }
+
+    #[test]
+    fn decode_shader_channel() {
+        let desc = ShaderDescriptor::Pixel(Encoding::DirectRgb);
+        for channel in [Channel::All, Channel::Ch1, Channel::Ch2] {
+            assert_eq!(
+                ShaderDescriptor::decode_with_channel(&desc.to_bytes_for(channel)),
+                Ok((desc, channel))
+            );
+        }
+        assert_eq!(desc.to_bytes_for(Channel::All), desc.to_bytes());
+        assert_eq!(
+            ShaderDescriptor::decode_with_channel(&[0, 2]),
+            Ok((ShaderDescriptor::Solid, Channel::Ch2))
+        );
+        assert_eq!(
+            ShaderDescriptor::decode_with_channel(&[0, 3]),
+            Err(ProtoError::UnknownChannel(3))
+        );
+    }
+
+    #[test]
+    fn decode_mapping() {
+        let mut w = VecWriter::<128>::new();
+        w.write_all(&[MessageType::BrainMapping as u8]).unwrap();
+        write_str(&mut w, "A1B2C3");
+        write_str_opt(&mut w, Some("F12"));
+        w.write_all(&7i32.to_be_bytes()).unwrap();
+        w.write_all(&2i32.to_be_bytes()).unwrap();
+        for v in [1.0f32, 2.0, 3.0, -1.0, -2.0, -3.0] {
+            w.write_all(&v.to_be_bytes()).unwrap();
+        }
+        let Ok(Message::BrainMapping(mapping)) = Message::decode(&w.buffer) else {
+            panic!("expected a mapping");
+        };
+        assert_eq!(mapping.brain_id, "A1B2C3");
+        assert_eq!(mapping.panel_name, Some("F12"));
+        assert_eq!(mapping.pixel_count, 7);
+        let mut coordinates = mapping.coordinates();
+        assert_eq!(coordinates.next(), Some([1.0, 2.0, 3.0]));
+        assert_eq!(coordinates.next(), Some([-1.0, -2.0, -3.0]));
+        assert_eq!(coordinates.next(), None);
+
+        // A coordinate count that would overflow the length is rejected.
+        let mut overflow = w.buffer.clone();
+        overflow.truncate(overflow.len() - 24);
+        let count_at = overflow.len() - 4;
+        overflow[count_at..].copy_from_slice(&i32::MAX.to_be_bytes());
+        assert!(Message::decode(&overflow).is_err());
+    }
+
+    #[test]
+    fn encode_hello() {
+        let msg = create_hello_msg("A1B2C3", Some("F12"), None);
+        assert_eq!(
+            Message::decode(&msg),
+            Ok(Message::BrainHello(BrainHello {
+                brain_id: "A1B2C3",
+                panel_name: Some("F12"),
+                firmware_version: None,
+                idf_version: None,
+                capabilities: None,
+            }))
+        );
+        assert_eq!(
+            msg.as_slice(),
+            &[
+                0, 0, 0, 0, 6, b'A', b'1', b'B', b'2', b'C', b'3', 1, 0, 0, 0, 3, b'F', b'1', b'2',
+                0, 0
+            ]
+        );
+    }
+
+    fn round_trip(encode: impl FnOnce(&mut VecWriter<FRAGMENT_MAX>), expected: Message) {
+        let mut w = VecWriter::new();
+        encode(&mut w);
+        assert_eq!(Message::decode(&w.buffer), Ok(expected));
+    }
+
+    proptest::proptest! {
+        #[test]
+        fn hello_round_trip(
+            brain_id in ".{0,64}",
+            panel_name in proptest::option::of(".{0,64}"),
+            firmware_version in proptest::option::of(".{0,64}"),
+            idf_version in proptest::option::of(".{0,64}"),
+            capabilities in proptest::option::of((
+                proptest::num::u8::ANY,
+                proptest::num::u32::ANY,
+                proptest::num::u32::ANY,
+                proptest::num::u8::ANY,
+                proptest::num::u32::ANY,
+            )),
+        ) {
+            let hello = BrainHello {
+                brain_id: &brain_id,
+                panel_name: panel_name.as_deref(),
+                firmware_version: firmware_version.as_deref(),
+                idf_version: idf_version.as_deref(),
+                capabilities: capabilities.map(
+                    |(protocol_version, encodings, max_leds, channels, features)| Capabilities {
+                        protocol_version,
+                        encodings,
+                        max_leds,
+                        channels,
+                        features: Features(features),
+                    },
+                ),
+            };
+            round_trip(|w| hello.encode(w), Message::BrainHello(hello.clone()));
+        }
+
+        #[test]
+        fn ping_round_trip(
+            data in proptest::collection::vec(proptest::num::u8::ANY, 0..=PONG_DATA_MAX),
+            is_pong: bool,
+            timestamps in proptest::option::of(
+                proptest::array::uniform3(proptest::num::u64::ANY)
+            ),
+        ) {
+            let ping = Ping {
+                data: heapless::Vec::from_slice(&data).unwrap(),
+                is_pong,
+                timestamps: timestamps.map(|[received_us, reassembled_us, displayed_us]| {
+                    PongTimestamps {
+                        received_us,
+                        reassembled_us,
+                        displayed_us,
+                    }
+                }),
+            };
+            assert!(ping.to_heapless().len() <= 64);
+            round_trip(|w| ping.encode(w), Message::Ping(ping.clone()));
+        }
+
+        #[test]
+        fn shade_round_trip(
+            pong_data in proptest::option::of(
+                proptest::collection::vec(proptest::num::u8::ANY, 0..=PONG_DATA_MAX)
+            ),
+            present_at_us in proptest::option::of(proptest::num::u64::ANY),
+            shader_data in proptest::collection::vec(proptest::num::u8::ANY, 0..64),
+        ) {
+            let desc = ShaderDescriptor::Pixel(Encoding::DirectRgb).to_bytes();
+            let shade = BrainPanelShade {
+                pong_data: pong_data.as_deref(),
+                present_at_us,
+                shader_desc: &desc,
+                shader_data: &shader_data,
+            };
+            round_trip(|w| shade.encode(w), Message::BrainPanelShade(shade.clone()));
+        }
+
+        #[test]
+        fn telemetry_round_trip(
+            uptime_ms: u64,
+            counts: [u32; 8],
+            reset_reason: u8,
+            link in 0u8..3,
+            ip: [u8; 4],
+            reboot_reason in proptest::option::of(".{0,16}"),
+        ) {
+            let [
+                frames_received,
+                frames_rendered,
+                frames_dropped,
+                fragment_resets,
+                late_frames,
+                missed_deltas,
+                free_heap,
+                min_free_heap,
+            ] = counts;
+            let telemetry = Telemetry {
+                uptime_ms,
+                frames_received,
+                frames_rendered,
+                frames_dropped,
+                fragment_resets,
+                late_frames,
+                missed_deltas,
+                free_heap,
+                min_free_heap,
+                reset_reason,
+                link: LinkType::from(link),
+                ip,
+                reboot_reason: reboot_reason.map(|reason| reason.as_str().try_into().unwrap()),
+            };
+            round_trip(|w| telemetry.encode(w), Message::Telemetry(telemetry.clone()));
+        }
+
+        #[test]
+        fn configure_round_trip(
+            led_counts: Option<[u16; 2]>,
+            max_fps: Option<u8>,
+            color_order in proptest::option::of(0u8..6),
+            gamma: Option<bool>,
+            brightness: Option<u8>,
+            liveness_timeout_ms: Option<u32>,
+            multicast_group: Option<[u8; 4]>,
+            dmx_universes: Option<(u16, u16)>,
+        ) {
+            let configure = Configure {
+                led_counts,
+                max_fps,
+                color_order: color_order.map(|order| ColorOrder::try_from(order).unwrap()),
+                gamma,
+                brightness,
+                liveness_timeout_ms,
+                multicast_group,
+                dmx_universes: dmx_universes.map(|(first, count)| DmxUniverses { first, count }),
+            };
+            round_trip(|w| configure.encode(w), Message::Configure(configure));
+        }
+
+        #[test]
+        fn identify_round_trip(duration_ms: u32) {
+            let identify = Identify { duration_ms };
+            round_trip(|w| identify.encode(w), Message::Identify(identify));
+        }
+
+        #[test]
+        fn reboot_round_trip(delay_ms: u32, reason in ".{0,64}") {
+            let reboot = Reboot { delay_ms, reason: &reason };
+            round_trip(|w| reboot.encode(w), Message::Reboot(reboot.clone()));
+        }
+
+        #[test]
+        fn use_firmware_round_trip(url in ".{0,256}") {
+            let use_firmware = UseFirmware { url: &url };
+            round_trip(|w| use_firmware.encode(w), Message::UseFirmware(use_firmware.clone()));
+        }
+
+        #[test]
+        fn rle_round_trip(
+            // Few distinct colors, so there are runs, some longer than 255.
+            pixels in proptest::collection::vec(0u8..3, 0..600),
+        ) {
+            let rgb: Vec<u8> = pixels.iter().flat_map(|&p| [p, p, 0]).collect();
+            let mut data = vec![];
+            write_rle(&mut data, &rgb);
+            let decoded: Vec<u8> = rle_runs(&data)
+                .flat_map(|(count, color)| core::iter::repeat_n(color, count).flatten())
+                .collect();
+            proptest::prop_assert_eq!(decoded, rgb);
+        }
+    }
+
+    #[test]
+    fn decode_configure() {
+        // Only brightness and liveness timeout.
+        let msg = [9, 0x30, 128, 0, 0, 0x27, 0x10];
+        assert_eq!(
+            Message::decode(&msg),
+            Ok(Message::Configure(Configure {
+                brightness: Some(128),
+                liveness_timeout_ms: Some(10_000),
+                ..Configure::default()
+            }))
+        );
+        assert_eq!(
+            Message::decode(&[9, 0x04, 6]),
+            Err(ProtoError::UnknownColorOrder(6))
+        );
+        assert_eq!(
+            Message::decode(&[9, 0x01, 0, 1]),
+            Err(ProtoError::Truncated)
+        );
+        assert_eq!(ColorOrder::Grb.arrange([1, 2, 3]), [2, 1, 3]);
+    }
+
+    #[test]
+    fn decode_delta() {
+        let mut data = vec![];
+        Patch {
+            start: 3,
+            rgb: &[1, 2, 3, 4, 5, 6],
+        }
+        .encode(&mut data);
+        Patch {
+            start: 9,
+            rgb: &[7, 8, 9],
+        }
+        .encode(&mut data);
+        let delta = Delta {
+            base_id: -2,
+            op: DeltaOp::Patch,
+            data: &data,
+        };
+        let mut encoded = vec![];
+        delta.encode(&mut encoded);
+        let decoded = Delta::decode(&encoded).unwrap();
+        assert_eq!(decoded, delta);
+        let patches: Vec<_> = decoded.patches().collect();
+        assert_eq!(patches.len(), 2);
+        assert_eq!(
+            patches[1],
+            Ok(Patch {
+                start: 9,
+                rgb: &[7, 8, 9]
+            })
+        );
+
+        // A cut-off patch is an error, and the last item.
+        let truncated = Delta {
+            data: &data[..data.len() - 1],
+            ..delta
+        };
+        let patches: Vec<_> = truncated.patches().collect();
+        assert_eq!(patches.len(), 2);
+        assert_eq!(patches[1], Err(ProtoError::Truncated));
+
+        assert_eq!(
+            Delta::decode(&[0, 1, 2]),
+            Err(ProtoError::UnsupportedShader)
+        );
+        assert_eq!(Delta::decode(&[0, 1]), Err(ProtoError::Truncated));
+    }
+
+    #[test]
+    fn capabilities_are_skippable() {
+        let capabilities = Capabilities {
+            protocol_version: PROTOCOL_VERSION,
+            encodings: Encoding::mask(&[Encoding::DirectRgb, Encoding::Indexed2]),
+            max_leds: 2048,
+            channels: 2,
+            features: Features::OTA | Features::MAPPING,
+        };
+        assert!(capabilities.supports(Encoding::Indexed2));
+        assert!(!capabilities.supports(Encoding::DirectArgb));
+        assert!(capabilities.features.contains(Features::MAPPING));
+        assert!(!capabilities.features.contains(Features::PONG_TIMESTAMPS));
+
+        let hello = BrainHello {
+            brain_id: "A1B2C3",
+            panel_name: None,
+            firmware_version: Some("rust-1"),
+            idf_version: Some("v5.3"),
+            capabilities: Some(capabilities),
+        };
+        let msg = hello.to_heapless();
+        // A reader that predates capabilities sees the same leading fields.
+        let mut r = Reader::new(&msg[1..]);
+        assert_eq!(r.read_str(), Ok("A1B2C3"));
+        assert_eq!(r.read_str_opt(), Ok(None));
+        assert_eq!(r.read_str_opt(), Ok(Some("rust-1")));
+        assert_eq!(r.read_str_opt(), Ok(Some("v5.3")));
+        assert_eq!(r.read_bytes().map(<[u8]>::len), Ok(Capabilities::SIZE));
+
+        // Fields added to the block later are ignored.
+        let mut block = heapless::Vec::<u8, 32>::from_slice(&capabilities.to_bytes()).unwrap();
+        block.extend_from_slice(&[1, 2, 3]).unwrap();
+        assert_eq!(Capabilities::decode(&block), Ok(capabilities));
+    }
+
+    #[test]
+    fn truncated_messages_are_errors() {
+        let msg = shade_msg(
+            Some(&[1, 2, 3]),
+            ShaderDescriptor::Pixel(Encoding::Indexed4),
+            &[0; 20],
+        );
+        // Every prefix that cuts into the fields before the pixel data.
+        let fields_end = 1 + 1 + 4 + 3 + 4 + 2 + 2 + 4 * 4;
+        for len in 0..fields_end {
+            let result = Message::decode(&msg.buffer[..len]).and_then(|msg| match msg {
+                Message::BrainPanelShade(shade) => shade.shader().map(|_| ()),
+                _ => Ok(()),
+            });
+            assert_eq!(result, Err(ProtoError::Truncated), "prefix of {len} bytes");
+        }
+        assert_eq!(
+            Message::decode(&[42]).err(),
+            Some(ProtoError::UnknownMessageType(42))
+        );
+    }
+}
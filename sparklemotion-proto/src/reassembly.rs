@@ -0,0 +1,271 @@
+//! Reassembly of fragmented messages. UDP gives no ordering guarantees, and on
+//! WiFi reordered fragments are common, so fragments are buffered per message
+//! and accepted in any order.
+
+use alloc::vec::Vec;
+use core::{net::SocketAddr, ops::Range};
+
+use crate::Header;
+
+/// Most partially received messages kept at once.
+const MAX_IN_FLIGHT: usize = 4;
+/// Largest message we'll buffer. Enough for ARGB data for every LED, or a
+/// mapping with coordinates for every LED.
+pub const MESSAGE_MAX: usize = 32 * 1024;
+/// Most bytes buffered across all partially received messages, as their
+/// sizes come from headers anyone on the network can send. When this or
+/// [`MAX_IN_FLIGHT`] is reached, fragments of new messages are dropped until
+/// a buffered one completes or goes stale.
+const BUFFERED_MAX: usize = 2 * MESSAGE_MAX;
+/// A message that hasn't completed within this long has lost a fragment.
+const STALE_AFTER_US: u64 = 500_000;
+
+/// A complete message.
+pub enum Reassembled<'a> {
+    /// Message arrived in a single frame, borrowed from the packet.
+    Frame(&'a [u8]),
+    /// Message pieced together from several frames. Hand the buffer back with
+    /// [`Reassembler::recycle`] once done with it.
+    Buffered(Vec<u8>),
+}
+
+impl core::ops::Deref for Reassembled<'_> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            Reassembled::Frame(msg) => msg,
+            Reassembled::Buffered(msg) => msg,
+        }
+    }
+}
+
+struct Partial {
+    from: SocketAddr,
+    id: i16,
+    first_seen_us: u64,
+    buf: Vec<u8>,
+    /// Sorted, non-overlapping byte ranges of `buf` received so far.
+    received: Vec<Range<usize>>,
+}
+
+impl Partial {
+    fn insert(&mut self, mut range: Range<usize>) {
+        // Merge with any ranges it overlaps or touches.
+        self.received.retain(|r| {
+            if r.start <= range.end && range.start <= r.end {
+                range.start = range.start.min(r.start);
+                range.end = range.end.max(r.end);
+                false
+            } else {
+                true
+            }
+        });
+        let idx = self.received.partition_point(|r| r.start < range.start);
+        self.received.insert(idx, range);
+    }
+
+    fn is_complete(&self) -> bool {
+        self.received.len() == 1 && self.received[0] == (0..self.buf.len())
+    }
+}
+
+#[derive(Default)]
+pub struct Reassembler {
+    partials: Vec<Partial>,
+    /// Allocation from a recycled message, reused for the next one.
+    spare: Vec<u8>,
+    /// Messages dropped before all of their fragments arrived.
+    pub incomplete: u32,
+}
+
+impl Reassembler {
+    /// Adds one frame that arrived at `now_us`. Returns the whole message
+    /// once every byte of it has arrived, along with when its first frame
+    /// arrived.
+    pub fn push<'a>(
+        &mut self,
+        from: SocketAddr,
+        header: &Header,
+        payload: &'a [u8],
+        now_us: u64,
+    ) -> Option<(Reassembled<'a>, u64)> {
+        let msg_size = header.msg_size as usize;
+        if header.frame_offset == 0 && payload.len() == msg_size {
+            return Some((Reassembled::Frame(payload), now_us));
+        }
+        if msg_size > MESSAGE_MAX {
+            return None;
+        }
+
+        let before = self.partials.len();
+        self.partials
+            .retain(|p| now_us.saturating_sub(p.first_seen_us) < STALE_AFTER_US);
+        self.incomplete += (before - self.partials.len()) as u32;
+
+        let idx = match self
+            .partials
+            .iter()
+            .position(|p| p.from == from && p.id == header.id)
+        {
+            // Same id but a different size means the sender's id wrapped
+            // around onto a message we never finished.
+            Some(idx) if self.partials[idx].buf.len() != msg_size => {
+                self.incomplete += 1;
+                let partial = self.partials.swap_remove(idx);
+                self.recycle(partial.buf);
+                self.add_partial(from, header, now_us)?
+            }
+            Some(idx) => idx,
+            None => self.add_partial(from, header, now_us)?,
+        };
+
+        let partial = &mut self.partials[idx];
+        let start = header.frame_offset as usize;
+        let range = start..start + payload.len();
+        partial.buf[range.clone()].copy_from_slice(payload);
+        partial.insert(range);
+
+        if partial.is_complete() {
+            let partial = self.partials.swap_remove(idx);
+            Some((Reassembled::Buffered(partial.buf), partial.first_seen_us))
+        } else {
+            None
+        }
+    }
+
+    /// Returns a buffer from [`Reassembled::Buffered`] for reuse.
+    pub fn recycle(&mut self, buf: Vec<u8>) {
+        if buf.capacity() > self.spare.capacity() {
+            self.spare = buf;
+        }
+    }
+
+    /// Starts buffering a message, if there is room for it. Returns its index
+    /// in `partials`.
+    fn add_partial(&mut self, from: SocketAddr, header: &Header, now_us: u64) -> Option<usize> {
+        let msg_size = header.msg_size as usize;
+        let buffered: usize = self.partials.iter().map(|p| p.buf.len()).sum();
+        if self.partials.len() >= MAX_IN_FLIGHT || buffered + msg_size > BUFFERED_MAX {
+            return None;
+        }
+        let mut buf = core::mem::take(&mut self.spare);
+        buf.clear();
+        buf.resize(msg_size, 0);
+        self.partials.push(Partial {
+            from,
+            id: header.id,
+            first_seen_us: now_us,
+            buf,
+            received: Vec::new(),
+        });
+        Some(self.partials.len() - 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::net::{IpAddr, Ipv4Addr};
+
+    use super::*;
+
+    const FROM: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)), 8002);
+
+    fn message(len: usize) -> Vec<u8> {
+        (0..len).map(|i| i as u8).collect()
+    }
+
+    /// Pushes `msg[range]` as a frame of message `id`.
+    fn push<'a>(
+        reassembler: &mut Reassembler,
+        id: i16,
+        msg: &'a [u8],
+        range: Range<usize>,
+        now_us: u64,
+    ) -> Option<(Reassembled<'a>, u64)> {
+        let header = Header {
+            id,
+            frame_size: range.len() as i16,
+            msg_size: msg.len() as i32,
+            frame_offset: range.start as i32,
+        };
+        reassembler.push(FROM, &header, &msg[range], now_us)
+    }
+
+    #[test]
+    fn single_frame_is_borrowed() {
+        let mut reassembler = Reassembler::default();
+        let msg = message(100);
+        let (got, first_seen_us) = push(&mut reassembler, 1, &msg, 0..100, 7).unwrap();
+        assert!(matches!(got, Reassembled::Frame(_)));
+        assert_eq!(&*got, &msg[..]);
+        assert_eq!(first_seen_us, 7);
+    }
+
+    #[test]
+    fn out_of_order_fragments() {
+        let mut reassembler = Reassembler::default();
+        let msg = message(3000);
+        assert!(push(&mut reassembler, 1, &msg, 2000..3000, 10).is_none());
+        assert!(push(&mut reassembler, 1, &msg, 0..1000, 20).is_none());
+        let (got, first_seen_us) = push(&mut reassembler, 1, &msg, 1000..2000, 30).unwrap();
+        assert_eq!(&*got, &msg[..]);
+        assert_eq!(first_seen_us, 10);
+        assert_eq!(reassembler.incomplete, 0);
+    }
+
+    #[test]
+    fn overlapping_fragments_leave_no_holes() {
+        let mut reassembler = Reassembler::default();
+        let msg = message(2000);
+        assert!(push(&mut reassembler, 1, &msg, 0..1000, 0).is_none());
+        // As many bytes as are missing, but half of them already here.
+        assert!(push(&mut reassembler, 1, &msg, 500..1500, 0).is_none());
+        assert!(push(&mut reassembler, 1, &msg, 0..1000, 0).is_none());
+        let (got, _) = push(&mut reassembler, 1, &msg, 1200..2000, 0).unwrap();
+        assert_eq!(&*got, &msg[..]);
+    }
+
+    #[test]
+    fn stale_fragments_are_dropped() {
+        let mut reassembler = Reassembler::default();
+        let msg = message(2000);
+        assert!(push(&mut reassembler, 1, &msg, 0..1000, 0).is_none());
+        // The rest arrives too late, so starts a message of its own.
+        assert!(push(&mut reassembler, 1, &msg, 1000..2000, STALE_AFTER_US).is_none());
+        assert_eq!(reassembler.incomplete, 1);
+        let (got, first_seen_us) =
+            push(&mut reassembler, 1, &msg, 0..1000, STALE_AFTER_US + 1).unwrap();
+        assert_eq!(&*got, &msg[..]);
+        assert_eq!(first_seen_us, STALE_AFTER_US);
+    }
+
+    #[test]
+    fn reused_id_starts_over() {
+        let mut reassembler = Reassembler::default();
+        let old = message(2000);
+        let new = message(3000);
+        assert!(push(&mut reassembler, 1, &old, 0..1000, 0).is_none());
+        assert!(push(&mut reassembler, 1, &new, 1000..3000, 0).is_none());
+        assert_eq!(reassembler.incomplete, 1);
+        let (got, _) = push(&mut reassembler, 1, &new, 0..1000, 0).unwrap();
+        assert_eq!(&*got, &new[..]);
+    }
+
+    #[test]
+    fn drops_new_messages_when_full() {
+        let mut reassembler = Reassembler::default();
+        let msg = message(2000);
+        for id in 0..MAX_IN_FLIGHT as i16 {
+            assert!(push(&mut reassembler, id, &msg, 0..1000, 0).is_none());
+        }
+        // No room to start another, so its first half is lost.
+        let id = MAX_IN_FLIGHT as i16;
+        assert!(push(&mut reassembler, id, &msg, 0..1000, 0).is_none());
+        assert!(push(&mut reassembler, 0, &msg, 1000..2000, 0).is_some());
+        assert!(push(&mut reassembler, id, &msg, 1000..2000, 0).is_none());
+
+        let too_big = message(MESSAGE_MAX + 1);
+        assert!(push(&mut reassembler, 9, &too_big, 0..1000, 0).is_none());
+    }
+}
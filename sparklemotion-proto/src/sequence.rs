@@ -0,0 +1,78 @@
+//! Serial number arithmetic ([RFC 1982](https://www.rfc-editor.org/rfc/rfc1982))
+//! for the i16 message id, so message order survives the id wrapping around.
+
+use core::cmp::Ordering;
+
+/// A message id. One id is newer than another when it is less than half the
+/// id space (2^15) ahead of it. Ids exactly half the space apart are
+/// unordered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SeqNum(pub i16);
+
+impl SeqNum {
+    pub fn is_newer_than(self, other: SeqNum) -> bool {
+        self > other
+    }
+}
+
+impl PartialOrd for SeqNum {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        match self.0.wrapping_sub(other.0) {
+            0 => Some(Ordering::Equal),
+            i16::MIN => None,
+            d if d > 0 => Some(Ordering::Greater),
+            _ => Some(Ordering::Less),
+        }
+    }
+}
+
+/// Ids for messages the brain sends. Kept apart from the ids of messages it
+/// receives; pinky and the brain each number their own messages.
+#[derive(Default)]
+pub struct IdCounter {
+    next: i16,
+}
+
+impl IdCounter {
+    pub fn next_id(&mut self) -> i16 {
+        let id = self.next;
+        self.next = id.wrapping_add(1);
+        id
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ordering_wraps_around() {
+        assert!(SeqNum(1).is_newer_than(SeqNum(0)));
+        assert!(!SeqNum(0).is_newer_than(SeqNum(1)));
+        assert!(SeqNum(i16::MIN).is_newer_than(SeqNum(i16::MAX)));
+        assert!(SeqNum(5).is_newer_than(SeqNum(-5)));
+        assert!(SeqNum(-30000).is_newer_than(SeqNum(30000)));
+        assert!(!SeqNum(7).is_newer_than(SeqNum(7)));
+    }
+
+    #[test]
+    fn half_the_space_apart_is_unordered() {
+        assert_eq!(SeqNum(0).partial_cmp(&SeqNum(i16::MIN)), None);
+        assert_eq!(SeqNum(i16::MIN).partial_cmp(&SeqNum(0)), None);
+        let opposite = 100i16.wrapping_add(i16::MIN);
+        assert!(!SeqNum(100).is_newer_than(SeqNum(opposite)));
+        assert!(!SeqNum(opposite).is_newer_than(SeqNum(100)));
+    }
+
+    #[test]
+    fn counter_wraps() {
+        let mut ids = IdCounter::default();
+        assert_eq!(ids.next_id(), 0);
+        let mut last = SeqNum(0);
+        for _ in 0..u16::MAX as usize + 10 {
+            let id = SeqNum(ids.next_id());
+            assert!(id.is_newer_than(last));
+            last = id;
+        }
+    }
+}